@@ -1,2 +1,4 @@
+pub mod anthropic;
+pub mod deepl;
 pub mod gemini;
 pub mod open_ai;
@@ -0,0 +1,238 @@
+use crate::error::Error;
+use log::{debug, info, trace, warn};
+use reqwest::Client;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+const API_VERSION: &str = "2023-06-01";
+/// The tool the model is made to call, its input is the structured output.
+const TOOL_NAME: &str = "translations";
+const TOOL_DESCRIPTION: &str = "Record the translation of every paragraph, in order.";
+
+#[derive(Serialize)]
+struct ClientRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    system: &'a str,
+    messages: Vec<MessageRequest>,
+    tools: Vec<Tool>,
+    tool_choice: ToolChoice,
+}
+
+#[derive(Serialize)]
+struct MessageRequest {
+    role: String,
+    content: Vec<Content>,
+}
+
+#[derive(Serialize)]
+struct Content {
+    #[serde(rename = "type")]
+    _type: String,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct Tool {
+    name: &'static str,
+    description: &'static str,
+    input_schema: Value,
+}
+
+#[derive(Serialize)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    _type: String,
+    name: &'static str,
+}
+
+#[derive(Deserialize)]
+struct ClientResponse {
+    #[serde(default)]
+    content: Vec<ContentBlock>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    _type: String,
+    #[serde(default)]
+    input: Value,
+}
+
+#[derive(Default, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+pub struct Stats {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// The `anthropic-ratelimit-*` headers, the ones a response leaves out are `None`.
+pub struct Ratelimit {
+    pub requests_limit: Option<String>,
+    pub requests_remaining: Option<String>,
+    pub requests_reset: Option<String>,
+    pub tokens_limit: Option<String>,
+    pub tokens_remaining: Option<String>,
+    pub tokens_reset: Option<String>,
+    /// Seconds to wait before the next request, sent with a 429 or 529 status.
+    pub retry_after: Option<String>,
+}
+
+impl Ratelimit {
+    fn parse(headers: &HeaderMap) -> Self {
+        Self {
+            requests_limit: header(headers, "anthropic-ratelimit-requests-limit"),
+            requests_remaining: header(headers, "anthropic-ratelimit-requests-remaining"),
+            requests_reset: header(headers, "anthropic-ratelimit-requests-reset"),
+            tokens_limit: header(headers, "anthropic-ratelimit-tokens-limit"),
+            tokens_remaining: header(headers, "anthropic-ratelimit-tokens-remaining"),
+            tokens_reset: header(headers, "anthropic-ratelimit-tokens-reset"),
+            retry_after: header(headers, "retry-after"),
+        }
+    }
+
+    pub fn log(&self) {
+        let headers = [
+            ("requests limit", &self.requests_limit),
+            ("requests remaining", &self.requests_remaining),
+            ("requests reset", &self.requests_reset),
+            ("tokens limit", &self.tokens_limit),
+            ("tokens remaining", &self.tokens_remaining),
+            ("tokens reset", &self.tokens_reset),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                debug!("ratelimit {name}: {value}");
+            }
+        }
+    }
+
+    /// How long to wait before the next request, when the API asked to retry later.
+    pub fn retry_after_duration(&self) -> Option<Duration> {
+        self.retry_after
+            .as_deref()
+            .and_then(|seconds| seconds.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+    }
+}
+
+pub struct Response {
+    pub stats: Stats,
+    /// Input of the tool call, `Null` when the response has none.
+    pub input: Value,
+    pub ratelimit: Ratelimit,
+}
+
+/// Sends the paragraphs with the `translations` tool the model is made to
+/// call, its input is `{"results": [{"translated": [string]}]}`.
+pub async fn request(
+    base_url: &str,
+    model: &str,
+    api_key: &str,
+    max_tokens: u32,
+    prompt: &str,
+    user_contents: &[String],
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let request_body = ClientRequest {
+        model,
+        max_tokens,
+        system: prompt,
+        messages: vec![MessageRequest {
+            role: "user".to_string(),
+            content: user_contents
+                .iter()
+                .map(|text| Content {
+                    _type: "text".to_string(),
+                    text: text.clone(),
+                })
+                .collect(),
+        }],
+        tools: vec![Tool {
+            name: TOOL_NAME,
+            description: TOOL_DESCRIPTION,
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "results": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "translated": {"type": "array", "items": {"type": "string"}}
+                            },
+                            "required": ["translated"]
+                        }
+                    }
+                },
+                "required": ["results"]
+            }),
+        }],
+        tool_choice: ToolChoice {
+            _type: "tool".to_string(),
+            name: TOOL_NAME,
+        },
+    };
+    let response = client
+        .post(format!("{}/v1/messages", base_url.trim_end_matches('/')))
+        .header("x-api-key", api_key)
+        .header("anthropic-version", API_VERSION)
+        .json(&request_body)
+        .send()
+        .await?;
+
+    let ratelimit = Ratelimit::parse(response.headers());
+    let status = response.status();
+    let response_text = response.text().await?;
+    if !status.is_success() {
+        info!("response status: {status}");
+        trace!("response error: {response_text}");
+        ratelimit.log();
+        // the retry is left to the caller, after the delay asked for
+        return Err(Error::Status {
+            status: status.as_u16(),
+            retry_after: ratelimit.retry_after_duration(),
+        });
+    }
+    let response_body: ClientResponse = serde_json::from_str(&response_text)?;
+
+    let input = response_body
+        .content
+        .into_iter()
+        .find(|block| block._type == "tool_use")
+        .map(|block| block.input);
+    if input.is_none() {
+        info!("response status: {status}");
+        trace!("response error: {response_text}");
+    }
+
+    let usage = response_body.usage.unwrap_or_else(|| {
+        warn!("the response has no usage");
+        Usage::default()
+    });
+    Ok(Response {
+        input: input.unwrap_or_default(),
+        ratelimit,
+        stats: Stats {
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+        },
+    })
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
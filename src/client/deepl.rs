@@ -0,0 +1,103 @@
+use crate::error::Error;
+use log::{info, trace};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The texts and settings of a translate request.
+#[derive(Serialize)]
+pub struct Request<'a> {
+    pub text: &'a [String],
+    pub target_lang: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_lang: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formality: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub glossary_id: Option<&'a str>,
+    /// Text that helps the translation, neither translated nor billed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ClientRequest<'a> {
+    #[serde(flatten)]
+    request: &'a Request<'a>,
+    tag_handling: &'static str,
+    show_billed_characters: bool,
+}
+
+#[derive(Deserialize)]
+struct ClientResponse {
+    #[serde(default)]
+    translations: Vec<Translation>,
+}
+
+#[derive(Deserialize)]
+struct Translation {
+    text: String,
+    billed_characters: Option<u64>,
+}
+
+pub struct Response {
+    pub texts: Vec<String>,
+    /// Characters billed for the request.
+    pub billed_characters: u64,
+}
+
+/// Translates the texts with `tag_handling=xml`, so XML tags in them are kept.
+pub async fn request(
+    base_url: &str,
+    api_key: &str,
+    request: &Request<'_>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let request_body = ClientRequest {
+        request,
+        tag_handling: "xml",
+        show_billed_characters: true,
+    };
+    let response = client
+        .post(format!("{}/v2/translate", base_url.trim_end_matches('/')))
+        .header("Authorization", format!("DeepL-Auth-Key {api_key}"))
+        .json(&request_body)
+        .send()
+        .await?;
+
+    let retry_after = response
+        .headers()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok()?.parse::<f64>().ok())
+        .map(Duration::from_secs_f64);
+    let status = response.status();
+    let response_text = response.text().await?;
+    if !status.is_success() {
+        info!("response status: {status}");
+        trace!("response error: {response_text}");
+        return Err(Error::Status {
+            status: status.as_u16(),
+            retry_after,
+        });
+    }
+    let response_body: ClientResponse = serde_json::from_str(&response_text)?;
+
+    let billed_characters = response_body
+        .translations
+        .iter()
+        .zip(request.text)
+        .map(|(translation, text)| {
+            translation
+                .billed_characters
+                .unwrap_or(text.chars().count() as u64)
+        })
+        .sum();
+    Ok(Response {
+        texts: response_body
+            .translations
+            .into_iter()
+            .map(|translation| translation.text)
+            .collect(),
+        billed_characters,
+    })
+}
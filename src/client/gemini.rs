@@ -1,5 +1,6 @@
-use log::{info, trace};
-use reqwest::{Client, Error};
+use crate::error::Error;
+use log::{info, trace, warn};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
@@ -41,13 +42,13 @@ struct Candidate {
     content: Content,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 struct UsageMetadata {
-    #[serde(rename = "promptTokenCount")]
+    #[serde(rename = "promptTokenCount", default)]
     prompt_token_count: i32,
-    #[serde(rename = "candidatesTokenCount")]
+    #[serde(rename = "candidatesTokenCount", default)]
     candidates_token_count: i32,
-    #[serde(rename = "totalTokenCount")]
+    #[serde(rename = "totalTokenCount", default)]
     total_token_count: i32,
 }
 
@@ -57,15 +58,6 @@ pub struct Stats {
     pub total_token_count: i32,
 }
 
-impl Stats {
-    pub fn log(&self) {
-        info!(
-            "prompt tokens: {} candidates tokens: {} total tokens: {}",
-            self.prompt_token_count, self.candidates_token_count, self.total_token_count
-        );
-    }
-}
-
 pub struct Response {
     pub stats: Stats,
     pub text: String,
@@ -82,17 +74,24 @@ pub async fn request(
     let url = format!(
         "https://generativelanguage.googleapis.com/v1beta/models/{model}:generateContent?key={api_key}"
     );
-    let response = client.post(url).json(&request_body).send().await;
-
-    if response.is_err() {
-        return Err(response.err().unwrap());
-    }
-    let response = response.unwrap();
+    let response = client.post(url).json(&request_body).send().await?;
 
+    let retry_after = response
+        .headers()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok()?.parse::<f64>().ok())
+        .map(Duration::from_secs_f64);
     let status = response.status();
-    let response_text = response.text().await.expect("API Response to Text error");
-    let response_body: ClientResponse =
-        serde_json::from_str(&response_text).expect("API Response to JSON error");
+    let response_text = response.text().await?;
+    if !status.is_success() {
+        info!("response status: {status}");
+        trace!("response error: {response_text}");
+        return Err(Error::Status {
+            status: status.as_u16(),
+            retry_after,
+        });
+    }
+    let response_body: ClientResponse = serde_json::from_str(&response_text)?;
 
     if response_body.candidates.is_empty() {
         info!("response status: {status}");
@@ -102,11 +101,13 @@ pub async fn request(
     let text = response_body
         .candidates
         .first()
-        .map_or("", |candidate| {
-            &candidate.content.parts.first().unwrap().text
-        })
+        .and_then(|candidate| candidate.content.parts.first())
+        .map_or("", |part| &part.text)
         .to_string();
-    let usage = response_body.usage_metadata.unwrap();
+    let usage = response_body.usage_metadata.unwrap_or_else(|| {
+        warn!("the response has no usageMetadata");
+        UsageMetadata::default()
+    });
 
     tokio::time::sleep(Duration::from_secs(30)).await;
 
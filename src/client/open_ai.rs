@@ -1,5 +1,7 @@
-use log::{debug, info, trace};
-use reqwest::{Client, Error};
+use crate::error::Error;
+use log::{debug, info, trace, warn};
+use reqwest::Client;
+use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
@@ -46,7 +48,7 @@ struct MessageResponse {
     content: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 struct Usage {
     prompt_tokens: i32,
     completion_tokens: i32,
@@ -59,41 +61,84 @@ pub struct Stats {
     pub total_tokens: i32,
 }
 
-impl Stats {
-    pub fn log(&self) {
-        info!(
-            "prompt tokens: {} completion tokens: {} total tokens: {}",
-            self.prompt_tokens, self.completion_tokens, self.total_tokens
-        );
-    }
-}
-
+/// The `x-ratelimit-*` headers, OpenAI compatible servers send some or none of them.
 pub struct Ratelimit {
-    pub limit_requests: String,
-    pub limit_tokens: String,
-    pub remaining_requests: String,
-    pub remaining_tokens: String,
-    pub reset_requests: String,
-    pub reset_tokens: String,
+    pub limit_requests: Option<String>,
+    pub limit_tokens: Option<String>,
+    pub remaining_requests: Option<String>,
+    pub remaining_tokens: Option<String>,
+    pub reset_requests: Option<String>,
+    pub reset_tokens: Option<String>,
+    /// Sent by Azure OpenAI with a 429 status, in milliseconds and in seconds.
+    pub retry_after_ms: Option<String>,
+    pub retry_after: Option<String>,
 }
 
 impl Ratelimit {
+    fn parse(headers: &HeaderMap) -> Self {
+        Self {
+            limit_requests: header(headers, "x-ratelimit-limit-requests"),
+            limit_tokens: header(headers, "x-ratelimit-limit-tokens"),
+            remaining_requests: header(headers, "x-ratelimit-remaining-requests"),
+            remaining_tokens: header(headers, "x-ratelimit-remaining-tokens"),
+            reset_requests: header(headers, "x-ratelimit-reset-requests"),
+            reset_tokens: header(headers, "x-ratelimit-reset-tokens"),
+            retry_after_ms: header(headers, "retry-after-ms"),
+            retry_after: header(headers, "retry-after"),
+        }
+    }
+
     pub fn log(&self) {
-        debug!("ratelimit limit requests: {}", self.limit_requests);
-        debug!("ratelimit limit tokens: {}", self.limit_tokens);
-        debug!("ratelimit remaining requests: {}", self.remaining_requests);
-        debug!("ratelimit remaining tokens: {}", self.remaining_tokens);
-        debug!("ratelimit reset requests: {}", self.reset_requests);
-        debug!("ratelimit reset tokens: {}", self.reset_tokens);
+        let headers = [
+            ("limit requests", &self.limit_requests),
+            ("limit tokens", &self.limit_tokens),
+            ("remaining requests", &self.remaining_requests),
+            ("remaining tokens", &self.remaining_tokens),
+            ("reset requests", &self.reset_requests),
+            ("reset tokens", &self.reset_tokens),
+            ("retry after ms", &self.retry_after_ms),
+            ("retry after", &self.retry_after),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                debug!("ratelimit {name}: {value}");
+            }
+        }
+    }
+
+    /// How long to wait before the next request, the retry delay when the
+    /// server asked for one, otherwise until the token limit resets.
+    pub fn wait_duration(&self) -> Duration {
+        self.retry_after_duration()
+            .unwrap_or_else(|| self.reset_tokens_duration())
     }
 
+    /// The retry delay the server asked for, `retry-after-ms` before `retry-after`.
+    pub fn retry_after_duration(&self) -> Option<Duration> {
+        if let Some(milliseconds) = self
+            .retry_after_ms
+            .as_deref()
+            .and_then(|milliseconds| milliseconds.parse::<f64>().ok())
+        {
+            return Some(Duration::from_secs_f64(milliseconds / 1000.0));
+        }
+        self.retry_after
+            .as_deref()
+            .and_then(|seconds| seconds.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+    }
+
+    /// How long until the token limit resets, zero without the header.
     pub fn reset_tokens_duration(&self) -> Duration {
+        let Some(reset_tokens) = &self.reset_tokens else {
+            return Duration::ZERO;
+        };
         // TODO: add format ex 6m0s
-        if let Some(milliseconds_str) = self.reset_tokens.strip_suffix("ms") {
+        if let Some(milliseconds_str) = reset_tokens.strip_suffix("ms") {
             if let Ok(milliseconds) = milliseconds_str.parse::<u64>() {
                 return Duration::from_millis(milliseconds);
             }
-        } else if let Some(seconds_str) = self.reset_tokens.strip_suffix('s')
+        } else if let Some(seconds_str) = reset_tokens.strip_suffix('s')
             && let Ok(seconds) = seconds_str.parse::<f64>()
         {
             return Duration::from_secs_f64(seconds);
@@ -108,87 +153,58 @@ pub struct Response {
     pub ratelimit: Ratelimit,
 }
 
+/// Sends the chat completion request to `url`, the `headers` carry the API key.
 pub async fn request(
+    url: &str,
+    headers: &[(String, String)],
     model: &str,
-    api_key: &String,
     prompt: &str,
     user_contents: &Vec<String>,
 ) -> Result<Response, Error> {
     let client = Client::new();
     let request_body = to_request_body(model, prompt, user_contents);
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {api_key}"))
-        .json(&request_body)
-        .send()
-        .await;
-
-    if response.is_err() {
-        return Err(response.err().unwrap());
+    let mut request = client.post(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
     }
-    let response = response.unwrap();
-    let headers = response.headers();
-    let ratelimit = Ratelimit {
-        limit_requests: headers
-            .get("x-ratelimit-limit-requests")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        limit_tokens: headers
-            .get("x-ratelimit-limit-tokens")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        remaining_requests: headers
-            .get("x-ratelimit-remaining-requests")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        remaining_tokens: headers
-            .get("x-ratelimit-remaining-tokens")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        reset_requests: headers
-            .get("x-ratelimit-reset-requests")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        reset_tokens: headers
-            .get("x-ratelimit-reset-tokens")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-    };
+    let response = request.json(&request_body).send().await?;
+
+    let ratelimit = Ratelimit::parse(response.headers());
 
     let status = response.status();
-    let response_text = response.text().await.expect("API Response to Text error");
-    let response_body: ClientResponse =
-        serde_json::from_str(&response_text).expect("API Response to JSON error");
+    let response_text = response.text().await?;
+    if !status.is_success() {
+        info!("response status: {status}");
+        trace!("response error: {response_text}");
+        ratelimit.log();
+        // the retry is left to the caller, after the delay asked for
+        return Err(Error::Status {
+            status: status.as_u16(),
+            retry_after: ratelimit.retry_after_duration(),
+        });
+    }
+    let response_body: ClientResponse = serde_json::from_str(&response_text)?;
 
     if response_body.choices.is_empty() {
         info!("response status: {status}");
         trace!("response error: {response_text}");
     }
 
-    debug!(
-        "sleep: {}sec",
-        ratelimit.reset_tokens_duration().as_secs_f64()
-    );
-    tokio::time::sleep(ratelimit.reset_tokens_duration()).await;
+    let wait = ratelimit.wait_duration();
+    if !wait.is_zero() {
+        debug!("sleep: {}sec", wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+    }
 
     let choice = response_body
         .choices
         .first()
         .map_or("", |choice| &choice.message.content)
         .to_string();
-    let usage = response_body.usage.unwrap();
+    let usage = response_body.usage.unwrap_or_else(|| {
+        warn!("the response has no usage");
+        Usage::default()
+    });
     Ok(Response {
         choice,
         ratelimit,
@@ -200,6 +216,13 @@ pub async fn request(
     })
 }
 
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
 fn to_request_body(
     model: &str,
     prompt: &str,
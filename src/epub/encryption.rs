@@ -0,0 +1,126 @@
+use crate::epub::attribute;
+use crate::error::Error;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+pub const ENCRYPTION_PATH: &str = "META-INF/encryption.xml";
+
+/// Font obfuscation algorithms, the fonts still work as long as the
+/// package unique identifier and `encryption.xml` are kept as they are.
+const FONT_OBFUSCATION: [&str; 2] = [
+    "http://www.idpf.org/2008/embedding",
+    "http://ns.adobe.com/pdf/enc#RC",
+];
+
+/// An `EncryptedData` entry of `META-INF/encryption.xml`.
+pub struct Encrypted {
+    pub algorithm: String,
+    pub uri: String,
+}
+
+impl Encrypted {
+    pub fn is_font_obfuscation(&self) -> bool {
+        FONT_OBFUSCATION.contains(&self.algorithm.as_str())
+    }
+}
+
+pub fn parse(content: &[u8]) -> Vec<Encrypted> {
+    let mut reader = Reader::from_reader(content);
+    reader.config_mut().trim_text(true);
+
+    let mut encrypted = vec![];
+    let mut algorithm = String::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.local_name().as_ref() {
+                b"EncryptedData" => algorithm.clear(),
+                // a key in `KeyInfo` has its own method, the first one is the data's
+                b"EncryptionMethod" if algorithm.is_empty() => {
+                    algorithm = attribute(&e, b"Algorithm").unwrap_or_default()
+                }
+                b"CipherReference" => {
+                    if let Some(uri) = attribute(&e, b"URI") {
+                        encrypted.push(Encrypted {
+                            algorithm: algorithm.clone(),
+                            uri,
+                        });
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    encrypted
+}
+
+/// Repeats the `EncryptedData` entry of every copied resource for its copy,
+/// `copies` are (original, copy) archive paths.
+pub fn add_copies(content: &[u8], copies: &[(String, String)]) -> Result<Vec<u8>, Error> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut entry: Vec<Event<'static>> = vec![];
+    let mut copy = None;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            event => event?.into_owned(),
+        };
+        match &event {
+            Event::Start(e) if e.local_name().as_ref() == b"EncryptedData" => {
+                entry.push(event);
+                continue;
+            }
+            Event::Start(e) | Event::Empty(e)
+                if !entry.is_empty() && e.local_name().as_ref() == b"CipherReference" =>
+            {
+                copy = attribute(e, b"URI").and_then(|uri| {
+                    copies
+                        .iter()
+                        .find(|(original, _)| *original == uri)
+                        .map(|(_, copy)| copy.clone())
+                });
+            }
+            _ => (),
+        }
+        if entry.is_empty() {
+            writer.write_event(event)?;
+            continue;
+        }
+        let end = matches!(&event, Event::End(e) if e.local_name().as_ref() == b"EncryptedData");
+        entry.push(event);
+        if end {
+            for event in &entry {
+                writer.write_event(event.borrow())?;
+            }
+            if let Some(copy) = copy.take() {
+                for event in &entry {
+                    match event {
+                        Event::Start(e) if e.local_name().as_ref() == b"CipherReference" => {
+                            writer.write_event(Event::Start(with_uri(e, &copy)))?
+                        }
+                        Event::Empty(e) if e.local_name().as_ref() == b"CipherReference" => {
+                            writer.write_event(Event::Empty(with_uri(e, &copy)))?
+                        }
+                        event => writer.write_event(event.borrow())?,
+                    }
+                }
+            }
+            entry.clear();
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+fn with_uri(e: &BytesStart, uri: &str) -> BytesStart<'static> {
+    let mut reference = BytesStart::new(String::from_utf8_lossy(e.name().0).into_owned());
+    reference.extend_attributes(
+        e.attributes()
+            .flatten()
+            .filter(|attribute| attribute.key.local_name().as_ref() != b"URI"),
+    );
+    reference.push_attribute(("URI", uri));
+    reference
+}
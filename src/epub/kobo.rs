@@ -0,0 +1,124 @@
+use crate::epub::attribute;
+use quick_xml::events::{BytesStart, Event};
+
+/// Class of the sentence spans Kobo uses to track the reading position.
+const KOBO_SPAN_CLASS: &str = "koboSpan";
+/// Closing quotes and brackets ending a sentence after its punctuation.
+const CLOSING: &str = "\"'”’」』)";
+
+/// Paragraph and sentence numbers of a `<span class="koboSpan" id="kobo.N.M">`.
+pub fn span_id(e: &BytesStart) -> Option<(usize, usize)> {
+    if e.local_name().as_ref() != b"span"
+        || !attribute(e, b"class")
+            .is_some_and(|class| class.split_whitespace().any(|c| c == KOBO_SPAN_CLASS))
+    {
+        return None;
+    }
+    let id = attribute(e, b"id")?;
+    let (paragraph, sentence) = id.strip_prefix("kobo.")?.split_once('.')?;
+    Some((paragraph.parse().ok()?, sentence.parse().ok()?))
+}
+
+/// Splits a text into sentences the way kepub spans do, whitespace and
+/// closing quotes staying with the sentence they follow.
+pub fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let end = match c {
+            '。' | '！' | '？' => true,
+            // not inside a number or a word, quotes may close first
+            '.' | '!' | '?' => chars
+                .clone()
+                .map(|(_, next)| next)
+                .find(|next| !CLOSING.contains(*next))
+                .is_none_or(char::is_whitespace),
+            _ => false,
+        };
+        if !end {
+            continue;
+        }
+        while let Some((_, next)) = chars.peek()
+            && (next.is_whitespace() || CLOSING.contains(*next))
+        {
+            chars.next();
+        }
+        let end = chars.peek().map_or(text.len(), |(index, _)| *index);
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+/// Wraps each sentence in a kobo span numbered from `kobo.{paragraph}.{first}`.
+pub fn spans(
+    paragraph: usize,
+    first: usize,
+    sentences: Vec<Vec<Event<'static>>>,
+) -> Vec<Event<'static>> {
+    let mut events = vec![];
+    for (sentence, events_of_sentence) in (first..).zip(sentences) {
+        let mut start = BytesStart::new("span");
+        start.push_attribute(("class", KOBO_SPAN_CLASS));
+        start.push_attribute(("id", format!("kobo.{paragraph}.{sentence}").as_str()));
+        let end = start.to_end().into_owned();
+        events.push(Event::Start(start));
+        events.extend(events_of_sentence);
+        events.push(Event::End(end));
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quick_xml::Reader;
+
+    fn start(tag: &str) -> BytesStart<'static> {
+        match Reader::from_str(tag).read_event().unwrap() {
+            Event::Start(e) => e.into_owned(),
+            event => panic!("{event:?}"),
+        }
+    }
+
+    #[test]
+    fn span_id_reads_kobo_spans_only() {
+        assert_eq!(
+            span_id(&start(r#"<span class="koboSpan" id="kobo.12.3">"#)),
+            Some((12, 3))
+        );
+        assert_eq!(
+            span_id(&start(r#"<span class="x koboSpan" id="kobo.1.1">"#)),
+            Some((1, 1))
+        );
+        assert_eq!(span_id(&start(r#"<span id="kobo.1.1">"#)), None);
+        assert_eq!(
+            span_id(&start(r#"<p class="koboSpan" id="kobo.1.1">"#)),
+            None
+        );
+        assert_eq!(
+            span_id(&start(r#"<span class="koboSpan" id="kobo.a.1">"#)),
+            None
+        );
+        assert_eq!(span_id(&start(r#"<span class="koboSpan" id="p1">"#)), None);
+    }
+
+    #[test]
+    fn sentences_keep_whitespace_and_closing_quotes() {
+        assert_eq!(sentences("One. Two! Three"), ["One. ", "Two! ", "Three"]);
+        assert_eq!(sentences("It is 3.14 here."), ["It is 3.14 here."]);
+        assert_eq!(
+            sentences(r#"He said "Stop." Then he left."#),
+            [r#"He said "Stop." "#, "Then he left."]
+        );
+        assert_eq!(sentences("(See above.)>>"), ["(See above.)>>"]);
+        assert_eq!(
+            sentences("「はい。」と言った。次へ"),
+            ["「はい。」", "と言った。", "次へ"]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use crate::epub::{attribute, percent_decode, resolve_href};
+use crate::error::Error;
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+pub const CONTAINER_PATH: &str = "META-INF/container.xml";
+pub const SMIL_MEDIA_TYPE: &str = "application/smil+xml";
+const PACKAGE_MEDIA_TYPE: &str = "application/oebps-package+xml";
+const RENDITION_NAMESPACE: &str = "http://www.idpf.org/2013/rendition";
+/// Media types of the content documents that get translated.
+const DOCUMENT_MEDIA_TYPES: [&str; 3] = ["application/xhtml+xml", "text/html", "image/svg+xml"];
+
+/// A manifest item, `href` resolved and decoded to an archive path.
+pub struct Item {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    pub media_overlay: Option<String>,
+    pub properties: Vec<String>,
+}
+
+/// A spine item reference.
+pub struct Itemref {
+    pub idref: String,
+    pub properties: Vec<String>,
+}
+
+/// A package document (OPF).
+pub struct Package {
+    pub path: String,
+    /// `version` of the package element, such as `3.0` or `2.0`.
+    pub version: String,
+    pub language: Option<String>,
+    /// `rendition:layout` of the whole package is `pre-paginated`.
+    pub pre_paginated: bool,
+    pub items: Vec<Item>,
+    pub itemrefs: Vec<Itemref>,
+}
+
+impl Package {
+    pub fn parse(path: &str, content: &[u8]) -> Self {
+        let mut reader = Reader::from_reader(content);
+        reader.config_mut().trim_text(true);
+
+        let mut version = String::new();
+        let mut language = None;
+        let mut pre_paginated = false;
+        let mut in_language = false;
+        let mut in_layout = false;
+        let mut items = vec![];
+        let mut itemrefs = vec![];
+        loop {
+            match reader.read_event() {
+                Ok(Event::Eof) | Err(_) => break,
+                Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"item" => {
+                    items.push(Item {
+                        id: attribute(&e, b"id").unwrap_or_default(),
+                        href: percent_decode(&resolve_href(
+                            path,
+                            &attribute(&e, b"href").unwrap_or_default(),
+                        )),
+                        media_type: attribute(&e, b"media-type").unwrap_or_default(),
+                        media_overlay: attribute(&e, b"media-overlay"),
+                        properties: attribute(&e, b"properties")
+                            .unwrap_or_default()
+                            .split_whitespace()
+                            .map(str::to_string)
+                            .collect(),
+                    });
+                }
+                Ok(Event::Start(e)) | Ok(Event::Empty(e))
+                    if e.local_name().as_ref() == b"itemref" =>
+                {
+                    itemrefs.push(Itemref {
+                        idref: attribute(&e, b"idref").unwrap_or_default(),
+                        properties: attribute(&e, b"properties")
+                            .unwrap_or_default()
+                            .split_whitespace()
+                            .map(str::to_string)
+                            .collect(),
+                    });
+                }
+                Ok(Event::Start(e)) => {
+                    if e.local_name().as_ref() == b"package" {
+                        version = attribute(&e, b"version").unwrap_or_default();
+                    }
+                    in_language = e.local_name().as_ref() == b"language";
+                    in_layout = e.local_name().as_ref() == b"meta"
+                        && attribute(&e, b"property").as_deref() == Some("rendition:layout");
+                }
+                Ok(Event::Text(e)) if in_language && language.is_none() => {
+                    language = e.decode().ok().map(|text| text.trim().to_string());
+                }
+                Ok(Event::Text(e)) if in_layout => {
+                    pre_paginated = e.decode().is_ok_and(|text| text.trim() == "pre-paginated");
+                }
+                Ok(Event::End(_)) => {
+                    in_language = false;
+                    in_layout = false;
+                }
+                _ => (),
+            }
+        }
+        Self {
+            path: path.to_string(),
+            version,
+            language,
+            pre_paginated,
+            items,
+            itemrefs,
+        }
+    }
+
+    /// Archive paths of the spine items laid out as fixed pages.
+    pub fn fixed_layout(&self) -> Vec<String> {
+        self.itemrefs
+            .iter()
+            .filter(|itemref| {
+                let properties = &itemref.properties;
+                properties
+                    .iter()
+                    .any(|p| p == "rendition:layout-pre-paginated")
+                    || (self.pre_paginated
+                        && !properties
+                            .iter()
+                            .any(|p| p == "rendition:layout-reflowable"))
+            })
+            .filter_map(|itemref| self.items.iter().find(|item| item.id == itemref.idref))
+            .map(|item| item.href.clone())
+            .collect()
+    }
+
+    /// Archive paths of the spine items, in reading order.
+    pub fn spine(&self) -> Vec<String> {
+        self.itemrefs
+            .iter()
+            .filter_map(|itemref| self.items.iter().find(|item| item.id == itemref.idref))
+            .map(|item| item.href.clone())
+            .collect()
+    }
+
+    /// Directory of the package document, the rendition's root.
+    pub fn directory(&self) -> &str {
+        self.path
+            .rsplit_once('/')
+            .map_or("", |(directory, _)| directory)
+    }
+
+    /// Archive paths of the content documents to translate.
+    pub fn documents(&self) -> Vec<String> {
+        self.items
+            .iter()
+            .filter(|item| DOCUMENT_MEDIA_TYPES.contains(&item.media_type.as_str()))
+            .map(|item| item.href.clone())
+            .collect()
+    }
+
+    pub fn has_media_overlays(&self) -> bool {
+        self.items
+            .iter()
+            .any(|item| item.media_overlay.is_some() || item.media_type == SMIL_MEDIA_TYPE)
+    }
+
+    /// Archive paths of the media overlay documents.
+    pub fn media_overlays(&self) -> Vec<String> {
+        self.items
+            .iter()
+            .filter(|item| item.media_type == SMIL_MEDIA_TYPE)
+            .map(|item| item.href.clone())
+            .collect()
+    }
+}
+
+/// Archive paths of the package documents listed in `META-INF/container.xml`.
+pub fn rootfiles(container: &[u8]) -> Vec<String> {
+    let mut reader = Reader::from_reader(container);
+    reader.config_mut().trim_text(true);
+
+    let mut rootfiles = vec![];
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"rootfile" => {
+                if let Some(path) = attribute(&e, b"full-path") {
+                    rootfiles.push(path);
+                }
+            }
+            _ => (),
+        }
+    }
+    rootfiles
+}
+
+/// Adds rootfiles for new renditions and maps the existing ones to their
+/// `rendition:language`, `languages` and `renditions` are (path, language) pairs.
+pub fn add_renditions(
+    container: &[u8],
+    languages: &[(String, String)],
+    renditions: &[(String, String)],
+) -> Result<Vec<u8>, Error> {
+    let mut reader = Reader::from_reader(container);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            event => event?,
+        };
+        match event {
+            Event::Start(mut e) if e.local_name().as_ref() == b"container" => {
+                if attribute(&e, b"xmlns:rendition").is_none() {
+                    e.push_attribute(("xmlns:rendition", RENDITION_NAMESPACE));
+                }
+                writer.write_event(Event::Start(e))?;
+            }
+            Event::Empty(mut e) if e.local_name().as_ref() == b"rootfile" => {
+                let language = attribute(&e, b"full-path").and_then(|path| {
+                    languages
+                        .iter()
+                        .find(|(other, _)| *other == path)
+                        .map(|(_, language)| language.clone())
+                });
+                if let Some(language) = language
+                    && attribute(&e, b"rendition:language").is_none()
+                {
+                    e.push_attribute(("rendition:language", language.as_str()));
+                }
+                writer.write_event(Event::Empty(e))?;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"rootfiles" => {
+                for (path, language) in renditions {
+                    let mut rootfile = BytesStart::new("rootfile");
+                    rootfile.push_attribute(("full-path", path.as_str()));
+                    rootfile.push_attribute(("media-type", PACKAGE_MEDIA_TYPE));
+                    rootfile.push_attribute(("rendition:language", language.as_str()));
+                    writer.write_event(Event::Empty(rootfile))?;
+                }
+                writer.write_event(Event::End(e))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// Replaces the text of the first `dc:language` of a package document.
+pub fn set_language(content: &[u8], language: &str) -> Result<Vec<u8>, Error> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut in_language = false;
+    let mut replaced = false;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            event => event?,
+        };
+        match event {
+            Event::Start(e) => {
+                in_language = !replaced && e.local_name().as_ref() == b"language";
+                writer.write_event(Event::Start(e))?;
+                if in_language {
+                    replaced = true;
+                    writer.write_event(Event::Text(BytesText::new(language)))?;
+                }
+            }
+            Event::Text(_) if in_language => (),
+            Event::End(e) => {
+                in_language = false;
+                writer.write_event(Event::End(e))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// Removes the media overlay items, `media-overlay` attributes and `media:` metadata.
+pub fn strip_media_overlays(content: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut skip_depth = 0;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            event => event?,
+        };
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Start(e) if is_media_overlay_metadata(&e) => skip_depth = 1,
+            Event::Empty(e) if is_media_overlay_metadata(&e) => (),
+            Event::Empty(e)
+                if e.local_name().as_ref() == b"item"
+                    && attribute(&e, b"media-type").as_deref() == Some(SMIL_MEDIA_TYPE) => {}
+            Event::Empty(e) if e.local_name().as_ref() == b"item" => {
+                let mut item = BytesStart::new(String::from_utf8_lossy(e.name().0).into_owned());
+                item.extend_attributes(
+                    e.attributes()
+                        .flatten()
+                        .filter(|attribute| attribute.key.0 != b"media-overlay"),
+                );
+                writer.write_event(Event::Empty(item))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+fn is_media_overlay_metadata(e: &BytesStart) -> bool {
+    matches!(e.local_name().as_ref(), b"meta" | b"link")
+        && [b"property".as_slice(), b"rel"].iter().any(|name| {
+            attribute(e, name).is_some_and(|value| {
+                value
+                    .split_whitespace()
+                    .any(|value| value.starts_with("media:"))
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_hrefs() {
+        let package = Package::parse(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><manifest><item id="c1" href="text/c%201.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="c1"/></spine></package>"#,
+        );
+        assert_eq!(package.documents(), ["OEBPS/text/c 1.xhtml"]);
+        assert_eq!(package.spine(), ["OEBPS/text/c 1.xhtml"]);
+    }
+}
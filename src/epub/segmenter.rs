@@ -0,0 +1,520 @@
+use crate::epub::{Options, attribute, kobo, percent_decode, resolve_href};
+use crate::translate::translator::Segment;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesEnd, BytesRef, BytesStart, BytesText, Event};
+use regex::Regex;
+use std::sync::LazyLock;
+
+const BLOCK_TAGS: [&[u8]; 8] = [b"p", b"h1", b"h2", b"h3", b"h4", b"h5", b"h6", b"li"];
+const CELL_TAGS: [&[u8]; 2] = [b"th", b"td"];
+const CODE_TAGS: [&[u8]; 6] = [b"code", b"kbd", b"samp", b"pre", b"var", b"tt"];
+const NOTE_TYPES: [&str; 4] = ["footnote", "endnote", "rearnote", "note"];
+const XHTML_NAMESPACE: &[u8] = b"http://www.w3.org/1999/xhtml";
+
+/// Text with nothing to translate, only digits, punctuation and symbols.
+static IGNORE_TEXT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap());
+/// Cell text of numbers, amounts and formulas.
+static NUMERIC_TEXT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[\s\p{Cc}\p{So}\p{Sc}\p{Sm}\p{N}\p{P}]*$").unwrap());
+
+/// Markup kept out of the segment text and restored verbatim in the translation.
+pub type Placeholder = Vec<Event<'static>>;
+
+/// The element a segment was read from.
+#[derive(Clone, PartialEq)]
+pub enum Kind {
+    Block,
+    Cell,
+    /// SVG `<text>`, with the `x` position of its first line.
+    SvgText {
+        x: Option<String>,
+    },
+    /// MathML `<mtext>`, translated as prose.
+    MathText,
+}
+
+/// A closed block with the segment to translate.
+pub struct Paragraph {
+    pub segment: Segment,
+    pub kind: Kind,
+    /// Whether the segment is inside the placeholder of another paragraph.
+    pub inner: bool,
+    pub placeholders: Vec<Placeholder>,
+    /// `path#id` of the footnote this paragraph belongs to.
+    pub note: Option<String>,
+    /// `path#id` of the footnotes referenced from this paragraph and the referencing sentence.
+    pub references: Vec<(String, String)>,
+    /// Paragraph and sentence numbers of the last kepub sentence span.
+    pub kobo: Option<(usize, usize)>,
+}
+
+struct Block {
+    tag: Vec<u8>,
+    depth: usize,
+    text: String,
+    context: Option<String>,
+    kind: Kind,
+    inner: bool,
+    prose: bool,
+    note: Option<String>,
+    placeholders: Vec<Placeholder>,
+    noterefs: Vec<(usize, String)>,
+    kobo: Option<(usize, usize)>,
+}
+
+impl Block {
+    fn new(tag: &[u8], kind: Kind, context: Option<String>, note: Option<String>) -> Self {
+        Self {
+            tag: tag.to_vec(),
+            depth: 1,
+            text: String::new(),
+            context,
+            kind,
+            inner: false,
+            prose: false,
+            note,
+            placeholders: vec![],
+            noterefs: vec![],
+            kobo: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    header: Vec<String>,
+    row: Vec<String>,
+    row_header: Option<String>,
+    first_th: bool,
+    header_row: bool,
+    in_thead: bool,
+    rows: usize,
+}
+
+impl Table {
+    fn context(&self) -> Option<String> {
+        let mut context = vec![];
+        if !self.header.is_empty() {
+            context.push(format!("Table header: {}", self.header.join(" | ")));
+        }
+        if let Some(row_header) = &self.row_header {
+            context.push(format!("Row header: {row_header}"));
+        }
+        if context.is_empty() {
+            None
+        } else {
+            Some(context.join("\n"))
+        }
+    }
+}
+
+struct Capture {
+    depth: usize,
+    events: Placeholder,
+    href: Option<String>,
+    text_start: Option<usize>,
+}
+
+/// Splits the events of a content document into translation segments.
+///
+/// Both the line collection and the write back walk the document with a
+/// `Segmenter`, so they always agree on which elements produce a segment.
+pub struct Segmenter {
+    path: String,
+    blocks: Vec<Block>,
+    tables: Vec<Table>,
+    notes: Vec<(usize, String)>,
+    capture: Option<Capture>,
+    depth: usize,
+    code_depth: usize,
+    svg_depth: usize,
+    translate_mtext: bool,
+}
+
+impl Segmenter {
+    pub fn new(path: &str, options: &Options) -> Self {
+        Self {
+            path: path.to_string(),
+            blocks: vec![],
+            tables: vec![],
+            notes: vec![],
+            capture: None,
+            depth: 0,
+            code_depth: 0,
+            svg_depth: 0,
+            translate_mtext: options.translate_mtext,
+        }
+    }
+
+    pub fn start(&mut self, e: &BytesStart) {
+        self.depth += 1;
+        let tag = e.name().0;
+        let local_name = e.local_name();
+        if let Some(capture) = self.capture.as_mut() {
+            capture.events.push(Event::Start(e.clone().into_owned()));
+        } else if let Some(block) = self.blocks.last_mut()
+            && ((tag == b"a" && has_type(e, "noteref")) || is_foreign(e))
+        {
+            block.placeholders.push(vec![]);
+            block
+                .text
+                .push_str(&format!("<x id=\"{}\"/>", block.placeholders.len()));
+            self.capture = Some(Capture {
+                depth: self.depth,
+                events: vec![Event::Start(e.clone().into_owned())],
+                href: (tag == b"a").then(|| attribute(e, b"href")).flatten(),
+                text_start: None,
+            });
+        }
+        if local_name.as_ref() == b"svg" {
+            self.svg_depth += 1;
+        }
+
+        // text nested in foreign content is translated even inside a placeholder
+        let inner = self.capture.is_some();
+        let note = self.notes.last().map(|(_, note)| note.clone());
+        match local_name.as_ref() {
+            b"text" if self.svg_depth > 0 => {
+                let x = attribute(e, b"x");
+                let mut block = Block::new(tag, Kind::SvgText { x }, None, note);
+                block.inner = inner;
+                if let Some(capture) = self.capture.as_mut() {
+                    capture.text_start = Some(capture.events.len());
+                }
+                self.blocks.push(block);
+                return;
+            }
+            b"tspan" if self.svg_depth > 0 => {
+                if let Some(block) = self.blocks.last_mut()
+                    && let Kind::SvgText { x } = &mut block.kind
+                {
+                    if x.is_none() {
+                        *x = attribute(e, b"x");
+                    }
+                    if !block.text.is_empty() && !block.text.ends_with(char::is_whitespace) {
+                        block.text.push(' ');
+                    }
+                }
+            }
+            b"mtext" if self.translate_mtext => {
+                let mut block = Block::new(tag, Kind::MathText, None, note);
+                block.inner = inner;
+                if let Some(capture) = self.capture.as_mut() {
+                    capture.text_start = Some(capture.events.len());
+                }
+                self.blocks.push(block);
+                return;
+            }
+            _ => (),
+        }
+        if inner {
+            return;
+        }
+
+        // kepub sentence spans are inline wrappers, sentences stay apart
+        if let Some(id) = kobo::span_id(e)
+            && let Some(block) = self.blocks.last_mut()
+        {
+            block.kobo = Some(id);
+            if block.text.ends_with(|c: char| c.is_ascii_punctuation()) {
+                block.text.push(' ');
+            }
+        }
+
+        if NOTE_TYPES.iter().any(|note| has_type(e, note))
+            && let Some(id) = attribute(e, b"id")
+        {
+            self.notes.push((self.depth, format!("{}#{id}", self.path)));
+        }
+        if CODE_TAGS.contains(&tag) {
+            self.code_depth += 1;
+        }
+        let note = self.notes.last().map(|(_, note)| note.clone());
+        if CELL_TAGS.contains(&tag)
+            && let Some(table) = self.tables.last_mut()
+        {
+            if tag == b"td" && !table.in_thead {
+                table.header_row = false;
+                if table.first_th && table.row.len() == 1 {
+                    table.row_header = table.row.first().cloned();
+                }
+            }
+            if table.row.is_empty() {
+                table.first_th = tag == b"th";
+            }
+            let context = table.context();
+            self.blocks.push(Block::new(tag, Kind::Cell, context, note));
+            return;
+        }
+        match tag {
+            b"table" => self.tables.push(Table::default()),
+            b"thead" => {
+                if let Some(table) = self.tables.last_mut() {
+                    table.in_thead = true;
+                }
+            }
+            b"tr" => {
+                if let Some(table) = self.tables.last_mut() {
+                    table.row.clear();
+                    table.row_header = None;
+                    table.header_row = true;
+                }
+            }
+            _ if BLOCK_TAGS.contains(&tag) => match self.blocks.last_mut() {
+                Some(block) if block.tag == tag => block.depth += 1,
+                Some(_) => (),
+                None => self.blocks.push(Block::new(tag, Kind::Block, None, note)),
+            },
+            _ => (),
+        }
+    }
+
+    pub fn empty(&mut self, e: &BytesStart) {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.events.push(Event::Empty(e.clone().into_owned()));
+        }
+    }
+
+    /// Returns the paragraph when `e` closes a block that should be translated.
+    pub fn end(&mut self, e: &BytesEnd) -> Option<Paragraph> {
+        let depth = self.depth;
+        self.depth -= 1;
+        let tag = e.name().0;
+        if e.local_name().as_ref() == b"svg" {
+            self.svg_depth = self.svg_depth.saturating_sub(1);
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            capture.events.push(Event::End(e.clone().into_owned()));
+            if capture.depth == depth
+                && let Some(capture) = self.capture.take()
+                && let Some(block) = self.blocks.last_mut()
+            {
+                let index = block.placeholders.len();
+                if let Some(href) = capture.href {
+                    block.noterefs.push((index, href));
+                }
+                if let Some(placeholder) = block.placeholders.last_mut() {
+                    *placeholder = capture.events;
+                }
+                return None;
+            }
+            if !self.blocks.last().is_some_and(|block| block.inner) {
+                return None;
+            }
+        } else {
+            if self
+                .notes
+                .last()
+                .is_some_and(|(note_depth, _)| *note_depth == depth)
+            {
+                self.notes.pop();
+            }
+            if CODE_TAGS.contains(&tag) {
+                self.code_depth = self.code_depth.saturating_sub(1);
+            }
+            match tag {
+                b"table" => {
+                    self.tables.pop();
+                }
+                b"thead" => {
+                    if let Some(table) = self.tables.last_mut() {
+                        table.in_thead = false;
+                    }
+                }
+                b"tr" => {
+                    if let Some(table) = self.tables.last_mut() {
+                        if table.in_thead
+                            || (table.header.is_empty() && table.rows == 0 && table.header_row)
+                        {
+                            table.header = table.row.clone();
+                        } else {
+                            table.rows += 1;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let block = self.blocks.last_mut()?;
+        if block.tag != tag {
+            return None;
+        }
+        block.depth -= 1;
+        if block.depth > 0 {
+            return None;
+        }
+        let block = self.blocks.pop()?;
+        let plain = placeholder_regex().replace_all(&block.text, "");
+        if block.kind == Kind::Cell {
+            if let Some(table) = self.tables.last_mut() {
+                table.row.push(plain.trim().to_string());
+            }
+            if !block.prose || NUMERIC_TEXT.is_match(&plain) {
+                return None;
+            }
+        } else if IGNORE_TEXT.is_match(&plain) {
+            return None;
+        }
+        let references = block
+            .noterefs
+            .iter()
+            .map(|(index, href)| {
+                (
+                    resolve_href(&self.path, &percent_decode(href)),
+                    self.referencing_sentence(&block.text, *index),
+                )
+            })
+            .collect();
+        Some(Paragraph {
+            segment: Segment {
+                text: block.text,
+                context: block.context,
+            },
+            kind: block.kind,
+            inner: block.inner,
+            placeholders: block.placeholders,
+            note: block.note,
+            references,
+            kobo: block.kobo,
+        })
+    }
+
+    /// Number of segments being collected, nested ones included.
+    pub fn open_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Whether the events are captured into a placeholder.
+    pub fn in_capture(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Whether the events are inside an XHTML block or table cell segment.
+    pub fn in_block(&self) -> bool {
+        self.blocks
+            .first()
+            .is_some_and(|block| matches!(block.kind, Kind::Block | Kind::Cell))
+    }
+
+    /// Whether the events are inside an SVG `<text>` segment.
+    pub fn in_svg_text(&self) -> bool {
+        self.blocks
+            .last()
+            .is_some_and(|block| matches!(block.kind, Kind::SvgText { .. }))
+    }
+
+    /// Replaces the text of the `<mtext>` or SVG `<text>` just closed inside a placeholder,
+    /// so the copy in the translation shows the translated text.
+    pub fn translate_captured_text(&mut self, line: &str) {
+        if let Some(capture) = self.capture.as_mut()
+            && let Some(start) = capture.text_start.take()
+        {
+            let rest = capture.events.split_off(start);
+            capture
+                .events
+                .push(Event::Text(BytesText::new(line).into_owned()));
+            capture.events.extend(
+                rest.into_iter()
+                    .filter(|event| !matches!(event, Event::Text(_))),
+            );
+        }
+    }
+
+    /// Takes a reference, CDATA or comment. A character or predefined entity
+    /// reference and CDATA are text, the rest is only kept in a placeholder.
+    pub fn other(&mut self, event: &Event) {
+        let text = match event {
+            Event::GeneralRef(e) => reference_text(e),
+            Event::CData(e) => Some(String::from_utf8_lossy(e).into_owned()),
+            _ => None,
+        };
+        match text {
+            Some(text) => self.text(&text),
+            None => {
+                if let Some(capture) = self.capture.as_mut() {
+                    capture.events.push(event.clone().into_owned());
+                }
+            }
+        }
+    }
+
+    pub fn text(&mut self, text: &str) {
+        if let Some(capture) = self.capture.as_mut() {
+            capture
+                .events
+                .push(Event::Text(BytesText::new(text).into_owned()));
+        }
+        if let Some(block) = self.blocks.last_mut()
+            && (self.capture.is_none() || block.inner)
+        {
+            block.text.push_str(text);
+            if self.code_depth == 0 && !IGNORE_TEXT.is_match(text) {
+                block.prose = true;
+            }
+        }
+    }
+
+    /// The sentence around the `index`th placeholder, without placeholders.
+    fn referencing_sentence(&self, text: &str, index: usize) -> String {
+        let marker = format!("<x id=\"{index}\"/>");
+        let Some(position) = text.find(&marker) else {
+            return String::new();
+        };
+        let is_end = |c: char| matches!(c, '.' | '!' | '?' | '。' | '！' | '？');
+        let before = text[..position].trim_end();
+        let (start, end) = if before.ends_with(is_end) {
+            let sentence = before.trim_end_matches(is_end);
+            (sentence.rfind(is_end).map_or(0, |i| i + 1), before.len())
+        } else {
+            let after = position + marker.len();
+            (
+                before.rfind(is_end).map_or(0, |i| i + 1),
+                text[after..]
+                    .find(is_end)
+                    .map_or(text.len(), |i| after + i + 1),
+            )
+        };
+        let start = (start..=end)
+            .find(|i| text.is_char_boundary(*i))
+            .unwrap_or(end);
+        let end = (end..=text.len())
+            .find(|i| text.is_char_boundary(*i))
+            .unwrap_or(text.len());
+        placeholder_regex()
+            .replace_all(&text[start..end], "")
+            .trim()
+            .to_string()
+    }
+}
+
+/// Matches a placeholder in the segment text, allowing the loose forms a model may return.
+pub fn placeholder_regex() -> &'static Regex {
+    static PLACEHOLDER: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"<x\s+id\s*=\s*["']?(\d+)["']?\s*/?>"#).unwrap());
+    &PLACEHOLDER
+}
+
+/// The text of a character reference or a predefined entity, `None` for
+/// an entity declared in the document type.
+fn reference_text(e: &BytesRef) -> Option<String> {
+    if e.is_char_ref() {
+        return e.resolve_char_ref().ok()?.map(String::from);
+    }
+    resolve_predefined_entity(&e.decode().ok()?).map(str::to_string)
+}
+
+fn has_type(e: &BytesStart, name: &str) -> bool {
+    attribute(e, b"epub:type").is_some_and(|types| types.split_whitespace().any(|t| t == name))
+        || attribute(e, b"role").is_some_and(|role| role == format!("doc-{name}"))
+}
+
+/// MathML, SVG and other non-XHTML elements are kept out of the segment text.
+fn is_foreign(e: &BytesStart) -> bool {
+    matches!(e.local_name().as_ref(), b"math" | b"svg")
+        || e.try_get_attribute("xmlns")
+            .ok()
+            .flatten()
+            .is_some_and(|xmlns| xmlns.value.as_ref() != XHTML_NAMESPACE)
+}
@@ -0,0 +1,479 @@
+use crate::epub::package::{CONTAINER_PATH, Package, rootfiles};
+use crate::epub::{MIMETYPE_PATH, attribute, percent_decode, resolve_href};
+use crate::error::Error;
+use quick_xml::Reader;
+use quick_xml::errors::IllFormedError;
+use quick_xml::escape::{EscapeError, resolve_predefined_entity};
+use quick_xml::events::{BytesStart, Event};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use zip::{CompressionMethod, ZipArchive};
+
+const MIMETYPE: &str = "application/epub+zip";
+const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+/// Attributes of content documents referencing another resource.
+const LINK_ATTRIBUTES: [&[u8]; 4] = [b"href", b"src", b"xlink:href", b"poster"];
+
+/// Output formats of `validate`.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Something that keeps a reader from opening the book, found in the entry at `path`.
+#[derive(Serialize)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(path: &str, message: impl Into<String>) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Checks the `mimetype` entry and `container.xml`, that the manifest items
+/// of every package exist and its spine references them, and that every
+/// content document is well-formed XML.
+pub fn validate<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    match archive.by_index(0) {
+        Ok(mut file) if file.name() == MIMETYPE_PATH => {
+            if file.compression() != CompressionMethod::Stored {
+                problems.push(Problem::new(MIMETYPE_PATH, "compressed"));
+            }
+            let mut content = String::new();
+            if file.read_to_string(&mut content).is_err() || content != MIMETYPE {
+                problems.push(Problem::new(
+                    MIMETYPE_PATH,
+                    format!("content is not {MIMETYPE}"),
+                ));
+            }
+        }
+        _ => problems.push(Problem::new(MIMETYPE_PATH, "not the first entry")),
+    }
+
+    let Some(container) = read(archive, CONTAINER_PATH) else {
+        problems.push(Problem::new(CONTAINER_PATH, "missing"));
+        return problems;
+    };
+    if let Err(e) = well_formed(&container) {
+        problems.push(Problem::new(CONTAINER_PATH, e.to_string()));
+    }
+    let paths = rootfiles(&container);
+    if paths.is_empty() {
+        problems.push(Problem::new(CONTAINER_PATH, "no rootfile"));
+    }
+    for path in paths {
+        let Some(content) = read(archive, &path) else {
+            problems.push(Problem::new(
+                CONTAINER_PATH,
+                format!("rootfile {path} missing"),
+            ));
+            continue;
+        };
+        if let Err(e) = well_formed(&content) {
+            problems.push(Problem::new(&path, e.to_string()));
+            continue;
+        }
+        let package = Package::parse(&path, &content);
+        for item in &package.items {
+            // remote resources are not in the archive
+            if !item.href.contains("://") && archive.index_for_name(&item.href).is_none() {
+                problems.push(Problem::new(
+                    &path,
+                    format!("manifest item {} missing: {}", item.id, item.href),
+                ));
+            }
+        }
+        for itemref in &package.itemrefs {
+            if !package.items.iter().any(|item| item.id == itemref.idref) {
+                problems.push(Problem::new(
+                    &path,
+                    format!("spine itemref {} is not in the manifest", itemref.idref),
+                ));
+            }
+        }
+        for document in package.documents() {
+            if let Some(content) = read(archive, &document)
+                && let Err(e) = well_formed(&content)
+            {
+                problems.push(Problem::new(&document, e.to_string()));
+            }
+        }
+    }
+    problems
+}
+
+/// Reads a document through, failing at the first markup error, an element
+/// left open or an entity that is neither predefined nor declared.
+pub fn well_formed(content: &[u8]) -> Result<(), quick_xml::Error> {
+    let mut reader = Reader::from_reader(content);
+    let mut open = vec![];
+    let mut doctype = false;
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::DocType(_) => doctype = true,
+            Event::Start(e) => open.push(e.name().as_ref().to_vec()),
+            Event::End(_) => {
+                open.pop();
+            }
+            Event::GeneralRef(e) if e.is_char_ref() => {
+                e.resolve_char_ref()?;
+            }
+            Event::GeneralRef(e) => {
+                let name = e.decode()?;
+                if !doctype && resolve_predefined_entity(&name).is_none() {
+                    let position = reader.buffer_position() as usize;
+                    return Err(EscapeError::UnrecognizedEntity(
+                        position..position,
+                        name.into_owned(),
+                    )
+                    .into());
+                }
+            }
+            _ => (),
+        }
+    }
+    match open.pop() {
+        Some(name) => {
+            Err(IllFormedError::MissingEndTag(String::from_utf8_lossy(&name).into_owned()).into())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Everything `validate` checks, and what epubcheck reports most often:
+/// duplicate and undeclared manifest items, a missing navigation document,
+/// duplicate ids and links to missing resources or fragment ids.
+pub fn check<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Vec<Problem> {
+    let mut problems = validate(archive);
+    let packages: Vec<Package> = read(archive, CONTAINER_PATH)
+        .map(|container| rootfiles(&container))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|path| read(archive, &path).map(|content| Package::parse(&path, &content)))
+        .collect();
+
+    let mut declared: HashSet<String> = HashSet::new();
+    let mut documents = vec![];
+    for package in &packages {
+        declared.insert(package.path.clone());
+        let mut ids = HashSet::new();
+        let mut hrefs = HashSet::new();
+        for item in &package.items {
+            if !ids.insert(&item.id) {
+                problems.push(Problem::new(
+                    &package.path,
+                    format!("duplicate manifest id {}", item.id),
+                ));
+            }
+            if !hrefs.insert(&item.href) {
+                problems.push(Problem::new(
+                    &package.path,
+                    format!("duplicate manifest item {}", item.href),
+                ));
+            }
+            declared.insert(item.href.clone());
+        }
+        if package.version.starts_with('3') {
+            if !package
+                .items
+                .iter()
+                .any(|item| item.properties.iter().any(|p| p == "nav"))
+            {
+                problems.push(Problem::new(&package.path, "no navigation document (nav)"));
+            }
+        } else if !package
+            .items
+            .iter()
+            .any(|item| item.media_type == NCX_MEDIA_TYPE)
+        {
+            problems.push(Problem::new(&package.path, "no NCX table of contents"));
+        }
+        documents.extend(package.documents());
+    }
+    for index in 0..archive.len() {
+        if let Some(name) = archive.name_for_index(index)
+            && name != MIMETYPE_PATH
+            && !name.starts_with("META-INF/")
+            && !name.ends_with('/')
+            && !declared.contains(name)
+        {
+            problems.push(Problem::new(name, "not declared in the manifest"));
+        }
+    }
+
+    // ids of every document first, links can point forward
+    let mut ids: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut links = vec![];
+    for document in documents {
+        let Some(content) = read(archive, &document) else {
+            continue;
+        };
+        if well_formed(&content).is_err() {
+            // reported by `validate`
+            continue;
+        }
+        let (document_ids, document_links) = ids_and_links(&content);
+        let mut seen = HashSet::new();
+        for id in &document_ids {
+            if !seen.insert(id) {
+                problems.push(Problem::new(&document, format!("duplicate id {id}")));
+            }
+        }
+        links.extend(
+            document_links
+                .into_iter()
+                .map(|link| (document.clone(), link)),
+        );
+        ids.insert(document, document_ids.into_iter().collect());
+    }
+    for (document, link) in links {
+        // the document is an archive path already, only the link is decoded
+        let (href, fragment) = match link.split_once('#') {
+            Some((href, fragment)) => (href, Some(fragment)),
+            None => (link.as_str(), None),
+        };
+        let path = if href.is_empty() {
+            document.clone()
+        } else {
+            resolve_href(&document, &percent_decode(href))
+        };
+        if archive.index_for_name(&path).is_none() {
+            problems.push(Problem::new(&document, format!("broken link {link}")));
+        } else if let Some(fragment) = fragment.filter(|fragment| !fragment.is_empty())
+            && let Some(ids) = ids.get(&path)
+            && !ids.contains(&percent_decode(fragment))
+        {
+            problems.push(Problem::new(&document, format!("broken fragment {link}")));
+        }
+    }
+    problems
+}
+
+/// Checks a book file with `check`.
+pub fn check_file(path: &Path) -> Result<Vec<Problem>, Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    Ok(check(&mut archive))
+}
+
+/// Writes the problems as `path: message` lines or as a JSON array.
+pub fn report(problems: &[Problem], format: Format, writer: &mut impl Write) -> Result<(), Error> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *writer, problems)?;
+            writeln!(writer)?;
+        }
+        Format::Text => {
+            for problem in problems {
+                writeln!(writer, "{}: {}", problem.path, problem.message)?;
+            }
+            writeln!(writer, "{} problems", problems.len())?;
+        }
+    }
+    Ok(())
+}
+
+/// The ids of a content document, in document order, and the links to
+/// resources in the book.
+fn ids_and_links(content: &[u8]) -> (Vec<String>, Vec<String>) {
+    let mut reader = Reader::from_reader(content);
+    let mut ids = vec![];
+    let mut links = vec![];
+    let mut visit = |e: &BytesStart| {
+        if let Some(id) = attribute(e, b"id") {
+            ids.push(id);
+        }
+        for name in LINK_ATTRIBUTES {
+            if let Some(link) = attribute(e, name)
+                && is_internal(&link)
+            {
+                links.push(link);
+            }
+        }
+    };
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => visit(&e),
+            _ => (),
+        }
+    }
+    (ids, links)
+}
+
+/// Whether a link points into the book, rather than to a URL with a scheme.
+fn is_internal(link: &str) -> bool {
+    let scheme = link.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    !link.is_empty() && link != "#" && !scheme
+}
+
+fn read<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Option<Vec<u8>> {
+    let mut file = archive.by_name(name).ok()?;
+    let mut content = vec![];
+    file.read_to_end(&mut content).ok()?;
+    Some(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    fn archive(entries: &[(&str, &str)]) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in entries {
+            let options = SimpleFileOptions::default();
+            let options = if *name == MIMETYPE_PATH {
+                options.compression_method(CompressionMethod::Stored)
+            } else {
+                options
+            };
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        ZipArchive::new(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encoded_hrefs_name_archive_paths() {
+        let mut archive = archive(&[
+            (MIMETYPE_PATH, MIMETYPE),
+            (CONTAINER_PATH, CONTAINER),
+            (
+                "OEBPS/content.opf",
+                r#"<package version="3.0"><manifest><item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/><item id="c1" href="c%201.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="c1"/></spine></package>"#,
+            ),
+            (
+                "OEBPS/nav.xhtml",
+                r##"<html><body><a href="c%201.xhtml#p1">1</a><a href="#top">top</a><p id="top"/></body></html>"##,
+            ),
+            (
+                "OEBPS/c 1.xhtml",
+                r#"<html><body><p id="p1">Hello.</p></body></html>"#,
+            ),
+        ]);
+        let problems = messages(check(&mut archive));
+        assert!(problems.is_empty(), "{problems:?}");
+    }
+
+    const CONTAINER: &str = r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#;
+
+    fn messages(problems: Vec<Problem>) -> Vec<String> {
+        problems
+            .into_iter()
+            .map(|problem| format!("{}: {}", problem.path, problem.message))
+            .collect()
+    }
+
+    #[test]
+    fn mimetype_is_the_first_entry_and_stored() {
+        let mut misordered = archive(&[(CONTAINER_PATH, CONTAINER), (MIMETYPE_PATH, MIMETYPE)]);
+        assert_eq!(
+            messages(validate(&mut misordered)),
+            [
+                "mimetype: not the first entry",
+                "META-INF/container.xml: rootfile OEBPS/content.opf missing",
+            ]
+        );
+
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        writer.start_file(MIMETYPE_PATH, deflated).unwrap();
+        writer.write_all(b"application/zip").unwrap();
+        let mut compressed = ZipArchive::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(
+            messages(validate(&mut compressed)),
+            [
+                "mimetype: compressed",
+                "mimetype: content is not application/epub+zip",
+                "META-INF/container.xml: missing",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_reports_manifest_ids_and_links() {
+        let mut archive = archive(&[
+            (MIMETYPE_PATH, MIMETYPE),
+            (CONTAINER_PATH, CONTAINER),
+            (
+                "OEBPS/content.opf",
+                r#"<package version="3.0"><manifest><item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/><item id="c1" href="c2.xhtml" media-type="application/xhtml+xml"/><item id="css" href="style.css" media-type="text/css"/><item id="css2" href="style.css" media-type="text/css"/></manifest><spine><itemref idref="c1"/></spine></package>"#,
+            ),
+            (
+                "OEBPS/c1.xhtml",
+                r#"<html><body><p id="a"/><p id="a"/><a href="missing.xhtml">x</a><a href="c2.xhtml#nowhere">y</a><a href="c2.xhtml#b">z</a></body></html>"#,
+            ),
+            ("OEBPS/c2.xhtml", r#"<html><body><p id="b"/></body></html>"#),
+            ("OEBPS/style.css", "p {}"),
+            ("OEBPS/extra.css", "p {}"),
+        ]);
+        assert_eq!(
+            messages(check(&mut archive)),
+            [
+                "OEBPS/content.opf: duplicate manifest id c1",
+                "OEBPS/content.opf: duplicate manifest item OEBPS/style.css",
+                "OEBPS/content.opf: no navigation document (nav)",
+                "OEBPS/extra.css: not declared in the manifest",
+                "OEBPS/c1.xhtml: duplicate id a",
+                "OEBPS/c1.xhtml: broken link missing.xhtml",
+                "OEBPS/c1.xhtml: broken fragment c2.xhtml#nowhere",
+            ]
+        );
+    }
+
+    #[test]
+    fn epub2_needs_an_ncx() {
+        let mut archive = archive(&[
+            (MIMETYPE_PATH, MIMETYPE),
+            (CONTAINER_PATH, CONTAINER),
+            (
+                "OEBPS/content.opf",
+                r#"<package version="2.0"><manifest><item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="c1"/></spine></package>"#,
+            ),
+            ("OEBPS/c1.xhtml", "<html><body><p>Hello.</p></body></html>"),
+        ]);
+        assert_eq!(
+            messages(check(&mut archive)),
+            ["OEBPS/content.opf: no NCX table of contents"]
+        );
+    }
+
+    #[test]
+    fn report_writes_text_or_json() {
+        let problems = [Problem::new("OEBPS/c1.xhtml", "duplicate id a")];
+        let mut text = vec![];
+        report(&problems, Format::Text, &mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "OEBPS/c1.xhtml: duplicate id a\n1 problems\n"
+        );
+        let mut json = vec![];
+        report(&problems, Format::Json, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{"path": "OEBPS/c1.xhtml", "message": "duplicate id a"}])
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("zip: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+
+    #[error("request: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("the response status is {status}")]
+    Status {
+        status: u16,
+        /// How long the server asked to wait before the next request.
+        retry_after: Option<Duration>,
+    },
+
+    #[error("the response has {translated} lines for {expected} paragraphs")]
+    LineCount { translated: usize, expected: usize },
+
+    #[error("{provider} does not support the language {language}")]
+    UnsupportedLanguage {
+        provider: &'static str,
+        language: String,
+    },
+
+    #[error("gave up after {retries} retries: {reason}")]
+    Retry { retries: i32, reason: String },
+
+    #[error(
+        "the book has media overlays, use `--media-overlay keep` or `--media-overlay strip` to translate it"
+    )]
+    MediaOverlay,
+
+    #[error("the book is encrypted (DRM protected) and cannot be translated: {}", .0.join(", "))]
+    Encrypted(Vec<String>),
+
+    #[error("no rendition {0} in META-INF/container.xml")]
+    Rendition(String),
+
+    #[error("{} exists, use --force to overwrite it", .0.display())]
+    OutputExists(PathBuf),
+
+    #[error("the output {} is the input, write the translation to another file", .0.display())]
+    InPlace(PathBuf),
+
+    #[error("the written book has {written} entries for {expected}")]
+    EntryCount { written: usize, expected: usize },
+}
+
+impl Error {
+    /// How long the server asked to wait before retrying, when it did.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
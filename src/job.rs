@@ -0,0 +1,107 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use trans_epub::translate::cache::Cache;
+
+const JOB_FILE: &str = "job.json";
+const CHECKPOINT_FILE: &str = "checkpoint.jsonl";
+const API_KEY_FLAGS: [&str; 2] = ["-a", "--api-key"];
+const FORCE_FLAG: &str = "--force";
+
+/// How a job was started, so `resume` can run it again.
+#[derive(Serialize, Deserialize)]
+struct Job {
+    directory: PathBuf,
+    /// Command line arguments without the API key.
+    arguments: Vec<String>,
+}
+
+/// Records the command line of a new job without `api_key`, an existing job
+/// is continued as is.
+pub fn start(job: &Path, api_key: &str) -> io::Result<()> {
+    let path = job.join(JOB_FILE);
+    if path.exists() {
+        info!("continue job {}", job.display());
+        return Ok(());
+    }
+    fs::create_dir_all(job)?;
+    let job = Job {
+        directory: std::env::current_dir()?,
+        arguments: without_api_key(std::env::args(), api_key),
+    };
+    fs::write(path, serde_json::to_vec_pretty(&job)?)
+}
+
+/// The checkpoint of a job, every translated segment is appended as it arrives.
+pub fn checkpoint(job: &Path) -> io::Result<Cache> {
+    Cache::open(&job.join(CHECKPOINT_FILE))
+}
+
+/// Command line arguments to resume a job, run from the directory it was started in.
+pub fn resume(job: &Path, api_key: Option<String>) -> io::Result<Vec<String>> {
+    let Job {
+        directory,
+        mut arguments,
+    } = serde_json::from_slice(&fs::read(job.join(JOB_FILE))?)?;
+    std::env::set_current_dir(directory)?;
+    // the output of the interrupted run is this job's own
+    if !arguments.iter().any(|argument| argument == FORCE_FLAG) {
+        arguments.push(FORCE_FLAG.to_string());
+    }
+    if let Some(api_key) = api_key {
+        arguments.extend([API_KEY_FLAGS[1].to_string(), api_key]);
+    }
+    info!("resume job {}", job.display());
+    Ok(arguments)
+}
+
+/// The arguments without the API key, given as `-a KEY`, `--api-key KEY`,
+/// `-aKEY`, `-a=KEY` or `--api-key=KEY`.
+fn without_api_key(arguments: impl Iterator<Item = String>, api_key: &str) -> Vec<String> {
+    let attached = [
+        format!("-a{api_key}"),
+        format!("-a={api_key}"),
+        format!("--api-key={api_key}"),
+    ];
+    let mut result = vec![];
+    let mut skip = false;
+    for argument in arguments {
+        if skip {
+            skip = false;
+        } else if API_KEY_FLAGS.contains(&argument.as_str()) {
+            skip = true;
+        } else if !attached.contains(&argument) {
+            result.push(argument);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> impl Iterator<Item = String> {
+        arguments.iter().map(|argument| argument.to_string())
+    }
+
+    #[test]
+    fn without_api_key_strips_only_the_key() {
+        let expected = ["trans-epub", "open-ai", "-i", "in.epub", "--header", "-abc"];
+        for key in [
+            &["-a", "KEY"][..],
+            &["--api-key", "KEY"],
+            &["-aKEY"],
+            &["-a=KEY"],
+            &["--api-key=KEY"],
+            &[],
+        ] {
+            let mut given = vec!["trans-epub", "open-ai", "-i", "in.epub"];
+            given.extend(key);
+            given.extend(["--header", "-abc"]);
+            assert_eq!(without_api_key(arguments(&given), "KEY"), expected);
+        }
+    }
+}
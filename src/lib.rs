@@ -0,0 +1,11 @@
+//! Translate EPUB books with a language model or a translation API, keeping the markup.
+//!
+//! [`epub::Epub`] reads a book, segments its content documents and writes the
+//! translated book. Translations come from a [`translate::translator::Driver`]
+//! around any [`translate::translator::Translator`], such as the OpenAI,
+//! Azure OpenAI, Gemini, Anthropic and DeepL backends or one of your own.
+
+mod client;
+pub mod epub;
+pub mod error;
+pub mod translate;
@@ -1,13 +1,23 @@
-mod client;
-mod epub;
-mod translate;
+mod job;
 
-use crate::epub::Epub;
-use crate::translate::translator::{Context, Translator};
 use clap::{Parser, Subcommand};
 use env_logger::Env;
-use log::debug;
-use std::path::PathBuf;
+use log::{debug, error, info, warn};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use trans_epub::epub::validate;
+use trans_epub::epub::{Epub, Failure, Options};
+use trans_epub::error::Error;
+use trans_epub::translate::anthropic::Anthropic;
+use trans_epub::translate::azure::Azure;
+use trans_epub::translate::cache::{self, Cache, CacheOptions, Format, Prune};
+use trans_epub::translate::deepl::{DeepL, Formality};
+use trans_epub::translate::gemini::Gemini;
+use trans_epub::translate::open_ai::OpenAi;
+use trans_epub::translate::translator::{Context, Driver, Translator};
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -40,6 +50,22 @@ enum SubCommands {
         #[arg(short, long, env, hide_env_values = true)]
         api_key: String,
 
+        /// OpenAI API base URL, or the one of a compatible server
+        #[arg(long, env = "OPENAI_BASE_URL", default_value_t = String::from("https://api.openai.com/v1"))]
+        base_url: String,
+
+        /// OpenAI organization ID
+        #[arg(long, env = "OPENAI_ORG_ID")]
+        organization: Option<String>,
+
+        /// OpenAI project ID
+        #[arg(long, env = "OPENAI_PROJECT_ID")]
+        project: Option<String>,
+
+        /// Header sent with every request, repeat it for more
+        #[arg(long = "header", value_name = "NAME:VALUE", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+
         /// Number of lines of translation
         #[arg(long, default_value_t = 20)]
         lines: usize,
@@ -47,6 +73,16 @@ enum SubCommands {
         /// Number of concurrent requests
         #[arg(long, default_value_t = 5)]
         requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+
+        #[command(flatten)]
+        cache: CacheOptions,
+
+        /// Job directory recording the translated chunks, continue it with `resume`
+        #[arg(long, value_name = "DIR")]
+        job: Option<PathBuf>,
     },
     /// Use Gemini API
     Gemini {
@@ -77,33 +113,487 @@ enum SubCommands {
         /// Number of concurrent requests
         #[arg(long, default_value_t = 1)]
         requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+
+        #[command(flatten)]
+        cache: CacheOptions,
+
+        /// Job directory recording the translated chunks, continue it with `resume`
+        #[arg(long, value_name = "DIR")]
+        job: Option<PathBuf>,
+    },
+    /// Use Azure OpenAI
+    Azure {
+        /// input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// output file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language
+        #[arg(short, long)]
+        language: String,
+
+        /// Azure OpenAI endpoint ex(https://{resource}.openai.azure.com)
+        #[arg(long, env = "AZURE_OPENAI_ENDPOINT")]
+        endpoint: String,
+
+        /// Deployment name of a chat completions model
+        #[arg(short, long)]
+        deployment: String,
+
+        /// Azure OpenAI API version
+        #[arg(long, env = "OPENAI_API_VERSION", default_value_t = String::from("2024-10-21"))]
+        api_version: String,
+
+        /// Azure OpenAI API Key
+        #[arg(short, long, env, hide_env_values = true)]
+        api_key: String,
+
+        /// Header sent with every request, repeat it for more
+        #[arg(long = "header", value_name = "NAME:VALUE", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 20)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 5)]
+        requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+
+        #[command(flatten)]
+        cache: CacheOptions,
+
+        /// Job directory recording the translated chunks, continue it with `resume`
+        #[arg(long, value_name = "DIR")]
+        job: Option<PathBuf>,
     },
+    /// Use Anthropic API
+    Anthropic {
+        /// input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// output file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language
+        #[arg(short, long)]
+        language: String,
+
+        /// Anthropic model ex(claude-3-5-haiku-latest, claude-sonnet-4-0)
+        #[arg(short, long, default_value_t = String::from("claude-3-5-haiku-latest"))]
+        model: String,
+
+        /// Anthropic API Key
+        #[arg(short, long, env, hide_env_values = true)]
+        api_key: String,
+
+        /// Anthropic API base URL
+        #[arg(long, env = "ANTHROPIC_BASE_URL", default_value_t = String::from("https://api.anthropic.com"))]
+        base_url: String,
+
+        /// Maximum number of tokens of a response
+        #[arg(long, default_value_t = 8192)]
+        max_tokens: u32,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 20)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 2)]
+        requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+
+        #[command(flatten)]
+        cache: CacheOptions,
+
+        /// Job directory recording the translated chunks, continue it with `resume`
+        #[arg(long, value_name = "DIR")]
+        job: Option<PathBuf>,
+    },
+    /// Use DeepL API
+    Deepl {
+        /// input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// output file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language, a name or a DeepL code ex(Japanese, JA, EN-GB)
+        #[arg(short, long)]
+        language: String,
+
+        /// source language, detected by default
+        #[arg(long, value_name = "LANGUAGE")]
+        source_language: Option<String>,
+
+        /// DeepL API Key, a key ending with `:fx` uses the free API
+        #[arg(short, long, env, hide_env_values = true)]
+        api_key: String,
+
+        /// DeepL API base URL, the free or pro API by the key by default
+        #[arg(long, env = "DEEPL_BASE_URL")]
+        base_url: Option<String>,
+
+        /// formality of the translation
+        #[arg(long, value_enum, default_value_t = Formality::Default)]
+        formality: Formality,
+
+        /// DeepL glossary ID, requires the source language
+        #[arg(long, value_name = "ID", requires = "source_language")]
+        glossary: Option<String>,
+
+        /// Number of lines of translation, at most 50
+        #[arg(long, default_value_t = 50)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 2)]
+        requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+
+        #[command(flatten)]
+        cache: CacheOptions,
+
+        /// Job directory recording the translated chunks, continue it with `resume`
+        #[arg(long, value_name = "DIR")]
+        job: Option<PathBuf>,
+    },
+    /// Resume an interrupted job
+    Resume {
+        /// job directory
+        job: PathBuf,
+
+        /// API Key, the job does not record it
+        #[arg(short, long, env, hide_env_values = true)]
+        api_key: Option<String>,
+    },
+    /// Inspect, prune or export the translation cache
+    Cache {
+        /// Translation cache file [default: ~/.cache/trans-epub/translations.jsonl]
+        #[arg(long, env = "TRANS_EPUB_CACHE", value_name = "FILE")]
+        cache: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        command: CacheCommands,
+    },
+    /// Check a book for common EPUB errors
+    Validate {
+        /// EPUB file path
+        file: PathBuf,
+
+        /// output format
+        #[arg(long, value_enum, default_value_t = validate::Format::Text)]
+        format: validate::Format,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Show the number of entries by provider, model and language
+    Stats,
+    /// Remove the entries matching every given filter
+    Prune {
+        /// entries older than this number of days
+        #[arg(long)]
+        older_than: Option<u64>,
+
+        /// entries of this provider (open-ai, azure-open-ai, gemini, anthropic, deepl)
+        #[arg(long)]
+        provider: Option<String>,
+
+        /// entries of this model
+        #[arg(long)]
+        model: Option<String>,
+
+        /// entries of this language
+        #[arg(long)]
+        language: Option<String>,
+
+        /// remove every entry
+        #[arg(long, conflicts_with_all = ["older_than", "provider", "model", "language"])]
+        all: bool,
+    },
+    /// Export the entries
+    Export {
+        /// output file path, standard output by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// export format
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+    },
+}
+
+/// Parses a `NAME:VALUE` header.
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("{header} is not NAME:VALUE"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("{header} has no name"));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// A job directory, given with `--job` or next to the output.
+struct JobDirectory {
+    path: PathBuf,
+    /// Not given with `--job`, it is kept only when the run is interrupted.
+    implicit: bool,
+    /// Created by this run, not left by an interrupted one.
+    created: bool,
+}
+
+impl JobDirectory {
+    /// Removes the directory when it is not given with `--job`. One left by an
+    /// interrupted run is kept when this run fails, it is still to be resumed.
+    fn remove(&self, failed: bool) {
+        if self.implicit
+            && (self.created || !failed)
+            && let Err(e) = fs::remove_dir_all(&self.path)
+        {
+            warn!("{}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Opens the job given with `--job`, or `OUTPUT.job` so that an interrupted
+/// run can be resumed too, with its checkpoint.
+fn open_job(job: Option<PathBuf>, output: &Path, api_key: &str) -> Option<(JobDirectory, Cache)> {
+    let implicit = job.is_none();
+    let path = job.unwrap_or_else(|| {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".job");
+        PathBuf::from(path)
+    });
+    let created = !path.exists();
+    match job::start(&path, api_key).and_then(|_| job::checkpoint(&path)) {
+        Ok(checkpoint) => Some((
+            JobDirectory {
+                path,
+                implicit,
+                created,
+            },
+            checkpoint,
+        )),
+        Err(e) if implicit => {
+            warn!(
+                "{}: {e}, an interrupted run cannot be resumed",
+                path.display()
+            );
+            None
+        }
+        Err(e) => {
+            error!("{}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exit code of a book written with untranslated segments.
+const PARTIAL_EXIT_CODE: i32 = 2;
+/// Exit code of an interrupted run, as shells report SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Exits with an error, or lists the untranslated segments and the problems
+/// of the output next to it. A job directory of its own is removed once the
+/// run is through.
+fn report(
+    failures: Result<Vec<Failure>, Error>,
+    output: &Path,
+    interrupted: &AtomicBool,
+    job: Option<&JobDirectory>,
+) {
+    let failures = match failures {
+        Ok(failures) => failures,
+        Err(e) => {
+            error!("{e}");
+            if let Some(job) = job {
+                job.remove(true);
+            }
+            std::process::exit(1);
+        }
+    };
+    let mut path = output.as_os_str().to_owned();
+    path.push(".failures.json");
+    let path = PathBuf::from(path);
+    if failures.is_empty() {
+        // a report left by an earlier run is not about this book any more
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            warn!("{}: {e}", path.display());
+        }
+    } else {
+        for failure in &failures {
+            warn!("{}: {}", failure.document, failure.error);
+        }
+        let written = serde_json::to_vec_pretty(&failures)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&path, content));
+        match written {
+            Ok(()) => error!(
+                "{} segments or documents are untranslated, see {}",
+                failures.len(),
+                path.display()
+            ),
+            Err(e) => error!("{}: {e}", path.display()),
+        }
+    }
+    if interrupted.load(Ordering::SeqCst) {
+        match job {
+            Some(job) => warn!(
+                "{} is partially translated, run `trans-epub resume {}` to continue",
+                output.display(),
+                job.path.display()
+            ),
+            None => warn!(
+                "{} is partially translated, run the same command again with --force to continue",
+                output.display()
+            ),
+        }
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    if let Some(job) = job {
+        job.remove(false);
+    }
+    if !failures.is_empty() {
+        std::process::exit(PARTIAL_EXIT_CODE);
+    }
+}
+
+/// Translates the book and reports the failures, logging the usage of the requests.
+async fn translate<T: Translator>(
+    translator: Driver<T>,
+    input: PathBuf,
+    output: &Path,
+    options: Options,
+    interrupted: &AtomicBool,
+    job: Option<&JobDirectory>,
+) {
+    let failures = Epub::new(input, output.to_path_buf(), options)
+        .translate(&translator)
+        .await;
+    info!("total {}", translator.usage());
+    report(failures, output, interrupted, job);
+}
+
+/// On the first SIGINT or SIGTERM no new request is issued and the book is
+/// written with what is translated, on the second the process exits.
+fn handle_signals(interrupted: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        signal().await;
+        warn!("interrupted, finishing the requests in flight, interrupt again to exit now");
+        interrupted.store(true, Ordering::SeqCst);
+        signal().await;
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    });
+}
+
+async fn signal() {
+    #[cfg(unix)]
+    if let Ok(mut terminate) =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+    {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+        return;
+    }
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     debug!("start");
-    let args = Args::parse();
+    let args = match Args::parse().subcommand {
+        SubCommands::Resume { job, api_key } => match job::resume(&job, api_key) {
+            Ok(arguments) => Args::parse_from(arguments),
+            Err(e) => {
+                error!("{}: {e}", job.display());
+                std::process::exit(1);
+            }
+        },
+        subcommand => Args { subcommand },
+    };
+    let interrupted = Arc::new(AtomicBool::new(false));
+    if matches!(
+        args.subcommand,
+        SubCommands::OpenAi { .. }
+            | SubCommands::Azure { .. }
+            | SubCommands::Gemini { .. }
+            | SubCommands::Anthropic { .. }
+            | SubCommands::Deepl { .. }
+    ) {
+        handle_signals(interrupted.clone());
+    }
     match args.subcommand {
         SubCommands::OpenAi {
             api_key,
             model,
+            base_url,
+            organization,
+            project,
+            headers,
             language,
             lines,
             requests,
             input,
             output,
+            options,
+            cache,
+            job,
         } => {
-            let translator = Translator::OpenAi(Context {
-                model,
-                api_key,
-                language,
-                lines,
-                requests,
-            });
-            let epub = Epub::new(input, output);
-            epub.translate(translator).await;
+            let (job, checkpoint) = open_job(job, &output, &api_key).unzip();
+            let translator = Driver::new(
+                OpenAi {
+                    base_url,
+                    organization,
+                    project,
+                    headers,
+                },
+                Context {
+                    model,
+                    api_key,
+                    language,
+                    lines,
+                    requests,
+                    cache: cache.open(),
+                    checkpoint,
+                    interrupted: interrupted.clone(),
+                },
+            );
+            translate(
+                translator,
+                input,
+                &output,
+                options,
+                &interrupted,
+                job.as_ref(),
+            )
+            .await;
         }
         SubCommands::Gemini {
             api_key,
@@ -113,16 +603,230 @@ async fn main() {
             requests,
             input,
             output,
+            options,
+            cache,
+            job,
         } => {
-            let translator = Translator::Gemini(Context {
-                model,
+            let (job, checkpoint) = open_job(job, &output, &api_key).unzip();
+            let translator = Driver::new(
+                Gemini,
+                Context {
+                    model,
+                    api_key,
+                    language,
+                    lines,
+                    requests,
+                    cache: cache.open(),
+                    checkpoint,
+                    interrupted: interrupted.clone(),
+                },
+            );
+            translate(
+                translator,
+                input,
+                &output,
+                options,
+                &interrupted,
+                job.as_ref(),
+            )
+            .await;
+        }
+        SubCommands::Azure {
+            endpoint,
+            deployment,
+            api_version,
+            api_key,
+            headers,
+            language,
+            lines,
+            requests,
+            input,
+            output,
+            options,
+            cache,
+            job,
+        } => {
+            let (job, checkpoint) = open_job(job, &output, &api_key).unzip();
+            let translator = Driver::new(
+                Azure {
+                    endpoint,
+                    deployment: deployment.clone(),
+                    api_version,
+                    headers,
+                },
+                Context {
+                    model: deployment,
+                    api_key,
+                    language,
+                    lines,
+                    requests,
+                    cache: cache.open(),
+                    checkpoint,
+                    interrupted: interrupted.clone(),
+                },
+            );
+            translate(
+                translator,
+                input,
+                &output,
+                options,
+                &interrupted,
+                job.as_ref(),
+            )
+            .await;
+        }
+        SubCommands::Anthropic {
+            api_key,
+            model,
+            base_url,
+            max_tokens,
+            language,
+            lines,
+            requests,
+            input,
+            output,
+            options,
+            cache,
+            job,
+        } => {
+            let (job, checkpoint) = open_job(job, &output, &api_key).unzip();
+            let translator = Driver::new(
+                Anthropic {
+                    base_url,
+                    max_tokens,
+                },
+                Context {
+                    model,
+                    api_key,
+                    language,
+                    lines,
+                    requests,
+                    cache: cache.open(),
+                    checkpoint,
+                    interrupted: interrupted.clone(),
+                },
+            );
+            translate(
+                translator,
+                input,
+                &output,
+                options,
+                &interrupted,
+                job.as_ref(),
+            )
+            .await;
+        }
+        SubCommands::Deepl {
+            api_key,
+            base_url,
+            language,
+            source_language,
+            formality,
+            glossary,
+            lines,
+            requests,
+            input,
+            output,
+            options,
+            cache,
+            job,
+        } => {
+            let deepl = match DeepL::new(
+                &api_key,
+                base_url,
+                &language,
+                source_language.as_deref(),
+                formality,
+                glossary,
+            ) {
+                Ok(deepl) => deepl,
+                Err(e) => {
+                    error!("{e}");
+                    std::process::exit(1);
+                }
+            };
+            let (job, checkpoint) = open_job(job, &output, &api_key).unzip();
+            let context = Context {
+                model: deepl.model(),
                 api_key,
                 language,
                 lines,
                 requests,
-            });
-            let epub = Epub::new(input, output);
-            epub.translate(translator).await;
+                cache: cache.open(),
+                checkpoint,
+                interrupted: interrupted.clone(),
+            };
+            translate(
+                Driver::new(deepl, context),
+                input,
+                &output,
+                options,
+                &interrupted,
+                job.as_ref(),
+            )
+            .await;
+        }
+        SubCommands::Resume { .. } => {
+            error!("a job cannot resume another job");
+            std::process::exit(1);
+        }
+        SubCommands::Cache { cache, command } => {
+            let Some(path) = cache.or_else(cache::default_path) else {
+                error!("no cache file, use --cache");
+                std::process::exit(1);
+            };
+            let result = match command {
+                CacheCommands::Stats => cache::stats(&path).map(|stats| print!("{stats}")),
+                CacheCommands::Prune {
+                    older_than,
+                    provider,
+                    model,
+                    language,
+                    all,
+                } => {
+                    if !all
+                        && older_than.is_none()
+                        && provider.is_none()
+                        && model.is_none()
+                        && language.is_none()
+                    {
+                        error!("give a filter, or --all to remove every entry");
+                        std::process::exit(1);
+                    }
+                    let prune = Prune {
+                        older_than_days: older_than,
+                        provider,
+                        model,
+                        language,
+                    };
+                    cache::prune(&path, &prune).map(|count| info!("removed {count} entries"))
+                }
+                CacheCommands::Export { output, format } => match output {
+                    Some(output) => File::create(output)
+                        .and_then(|mut file| cache::export(&path, format, &mut file)),
+                    None => cache::export(&path, format, &mut std::io::stdout().lock()),
+                },
+            };
+            if let Err(e) = result {
+                error!("{}: {e}", path.display());
+                std::process::exit(1);
+            }
+        }
+        SubCommands::Validate { file, format } => {
+            let problems = match validate::check_file(&file) {
+                Ok(problems) => problems,
+                Err(e) => {
+                    error!("{}: {e}", file.display());
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = validate::report(&problems, format, &mut std::io::stdout().lock()) {
+                error!("{e}");
+                std::process::exit(1);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
         }
     }
     debug!("end");
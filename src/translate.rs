@@ -1,3 +1,7 @@
-mod gemini;
-mod open_ai;
+pub mod anthropic;
+pub mod azure;
+pub mod cache;
+pub mod deepl;
+pub mod gemini;
+pub mod open_ai;
 pub mod translator;
@@ -0,0 +1,77 @@
+use crate::client::anthropic::request;
+use crate::error::Error;
+use crate::translate::translator::{Batch, Context, Segment, Translator, Usage, paragraphs};
+use log::error;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct ToolInput {
+    results: Vec<ToolInputResult>,
+}
+
+#[derive(Deserialize)]
+struct ToolInputResult {
+    translated: Vec<String>,
+}
+
+/// The Anthropic Messages API, the paragraphs come back as the input of a tool call.
+pub struct Anthropic {
+    /// Base URL of the API, without `/v1/messages`.
+    pub base_url: String,
+    /// Most tokens of a response.
+    pub max_tokens: u32,
+}
+
+impl Translator for Anthropic {
+    fn provider(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn prompt_version(&self) -> u32 {
+        1
+    }
+
+    async fn translate_batch(&self, context: &Context, lines: &[Segment]) -> Result<Batch, Error> {
+        let prompt = format!(
+            "You are an excellent translator.\
+            Translate it into {}. Please output the translation with the `translations` tool.\
+            A string in `<paragraph>` tag to `</paragraph>` tag is one paragraph.\
+            There are {} paragraphs of input, please output {} results in the order of the input.\
+            The value of `translated` Key is an array of String type.\
+            If a paragraph of input is translated and a paragraph consists of multiple sentences, output an array consisting of multiple String.\
+            Please remove `<paragraph>` and `</paragraph>` tags from the translation result.\
+            A string in `<context>` tag to `</context>` tag is not a paragraph but reference information for the following paragraphs, please do not output it.\
+            Please keep `<x id=\"1\"/>` style tags in a paragraph unchanged at the corresponding position of the translation result.",
+            context.language,
+            lines.len(),
+            lines.len()
+        );
+
+        let response = request(
+            &self.base_url,
+            &context.model,
+            &context.api_key,
+            self.max_tokens,
+            &prompt,
+            &paragraphs(lines),
+        )
+        .await?;
+        response.ratelimit.log();
+        let tool_input = serde_json::from_value::<ToolInput>(response.input.clone())
+            .inspect_err(|_| error!("JSON Parse error input:{}", response.input))?;
+
+        Ok(Batch {
+            lines: tool_input
+                .results
+                .into_iter()
+                .map(|result| result.translated.join("\n"))
+                .collect(),
+            usage: Usage {
+                prompt_tokens: response.stats.input_tokens,
+                completion_tokens: response.stats.output_tokens,
+                total_tokens: response.stats.input_tokens + response.stats.output_tokens,
+                ..Usage::default()
+            },
+        })
+    }
+}
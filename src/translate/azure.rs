@@ -0,0 +1,36 @@
+use crate::error::Error;
+use crate::translate::open_ai::{PROMPT_VERSION, chat_completions};
+use crate::translate::translator::{Batch, Context, Segment, Translator};
+
+/// A chat completions deployment of Azure OpenAI, with the prompt of the OpenAI API.
+pub struct Azure {
+    /// Endpoint of the resource, `https://{resource}.openai.azure.com`.
+    pub endpoint: String,
+    pub deployment: String,
+    /// The `api-version` query parameter.
+    pub api_version: String,
+    /// Headers sent with every request, besides the API key.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Translator for Azure {
+    fn provider(&self) -> &'static str {
+        "azure-open-ai"
+    }
+
+    fn prompt_version(&self) -> u32 {
+        PROMPT_VERSION
+    }
+
+    async fn translate_batch(&self, context: &Context, lines: &[Segment]) -> Result<Batch, Error> {
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            self.deployment,
+            self.api_version
+        );
+        let mut headers = vec![("api-key".to_string(), context.api_key.clone())];
+        headers.extend(self.headers.iter().cloned());
+        chat_completions(&url, &headers, context, lines).await
+    }
+}
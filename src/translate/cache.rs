@@ -0,0 +1,431 @@
+use crate::translate::translator::Segment;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg_attr(feature = "cli", derive(clap::Args))]
+pub struct CacheOptions {
+    /// Translation cache file [default: ~/.cache/trans-epub/translations.jsonl]
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "TRANS_EPUB_CACHE", value_name = "FILE")
+    )]
+    pub cache: Option<PathBuf>,
+
+    /// Do not read or write the translation cache
+    #[cfg_attr(feature = "cli", arg(long))]
+    pub no_cache: bool,
+}
+
+impl CacheOptions {
+    /// Opens the cache, translating without it when the file cannot be used.
+    pub fn open(self) -> Option<Cache> {
+        if self.no_cache {
+            return None;
+        }
+        let path = self.cache.or_else(default_path)?;
+        match Cache::open(&path) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("translation cache {} disabled: {e}", path.display());
+                None
+            }
+        }
+    }
+}
+
+/// What a cached translation was made with.
+pub struct Key<'a> {
+    pub provider: &'a str,
+    pub model: &'a str,
+    pub language: &'a str,
+    pub prompt_version: u32,
+}
+
+impl Key<'_> {
+    /// Hash of everything the translation of a segment depends on.
+    pub fn hash(&self, segment: &Segment) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            self.provider,
+            self.model,
+            self.language,
+            &self.prompt_version.to_string(),
+            segment.context.as_deref().unwrap_or_default(),
+            &segment.text,
+        ] {
+            // length prefixed, so the parts cannot run into each other
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// A line of the cache file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    pub provider: String,
+    pub model: String,
+    pub language: String,
+    pub source: String,
+    pub translation: String,
+    /// Unix time the translation was made.
+    pub created: u64,
+}
+
+/// Translations stored as JSON Lines, appended as they arrive so an
+/// interrupted run keeps what it paid for. The last line of a key wins,
+/// the older ones are dropped when the cache is opened.
+pub struct Cache {
+    entries: Mutex<HashMap<String, Entry>>,
+    file: Mutex<File>,
+}
+
+impl Cache {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let (entries, lines) = read_lines(path)?;
+        if lines > entries.len() {
+            write_entries(path, &entries)?;
+        }
+        let entries = entries
+            .into_iter()
+            .map(|entry| (entry.key.clone(), entry))
+            .collect::<HashMap<_, _>>();
+        info!(
+            "translation cache {} ({} entries)",
+            path.display(),
+            entries.len()
+        );
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            entries: Mutex::new(entries),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).map(|entry| entry.translation.clone())
+    }
+
+    pub fn insert(&self, key: &Key, segment: &Segment, translation: &str) {
+        let entry = Entry {
+            key: key.hash(segment),
+            provider: key.provider.to_string(),
+            model: key.model.to_string(),
+            language: key.language.to_string(),
+            source: segment.text.clone(),
+            translation: translation.to_string(),
+            created: now(),
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{line}") {
+            warn!("translation cache write fail: {e}");
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.key.clone(), entry);
+    }
+}
+
+/// `$XDG_CACHE_HOME/trans-epub/translations.jsonl`, or under `~/.cache`.
+pub fn default_path() -> Option<PathBuf> {
+    let directory = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(directory.join("trans-epub").join("translations.jsonl"))
+}
+
+/// Entries of a cache file, the latest one of each key. Lines that do not
+/// parse, such as the last line of an interrupted write, are skipped.
+pub fn read_entries(path: &Path) -> io::Result<Vec<Entry>> {
+    Ok(read_lines(path)?.0)
+}
+
+/// Entries of a cache file and the number of lines they are read from.
+fn read_lines(path: &Path) -> io::Result<(Vec<Entry>, usize)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e),
+    };
+    let mut entries: Vec<Entry> = vec![];
+    let mut indexes = HashMap::new();
+    let mut lines = 0;
+    for line in BufReader::new(file).lines() {
+        lines += 1;
+        let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+            continue;
+        };
+        match indexes.get(&entry.key) {
+            Some(&index) => entries[index] = entry,
+            None => {
+                indexes.insert(entry.key.clone(), entries.len());
+                entries.push(entry);
+            }
+        }
+    }
+    Ok((entries, lines))
+}
+
+/// Replaces a cache file with the given entries, through a temporary file
+/// so a failure leaves the old cache in place.
+pub fn write_entries(path: &Path, entries: &[Entry]) -> io::Result<()> {
+    let temporary = path.with_extension("jsonl.tmp");
+    let mut file = File::create(&temporary)?;
+    for entry in entries {
+        writeln!(file, "{}", serde_json::to_string(entry).unwrap())?;
+    }
+    file.sync_all()?;
+    fs::rename(temporary, path)
+}
+
+/// Entry counts of a cache file by provider, model and language.
+pub fn stats(path: &Path) -> io::Result<String> {
+    let entries = read_entries(path)?;
+    let size = fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let mut counts: BTreeMap<(&str, &str, &str), usize> = BTreeMap::new();
+    for entry in &entries {
+        *counts
+            .entry((&entry.provider, &entry.model, &entry.language))
+            .or_default() += 1;
+    }
+    let mut stats = format!(
+        "{}\n{} entries, {size} bytes\n",
+        path.display(),
+        entries.len()
+    );
+    for ((provider, model, language), count) in counts {
+        stats.push_str(&format!("{provider}\t{model}\t{language}\t{count}\n"));
+    }
+    Ok(stats)
+}
+
+/// Which entries `prune` removes, an entry must match every filter given.
+pub struct Prune {
+    pub older_than_days: Option<u64>,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub language: Option<String>,
+}
+
+impl Prune {
+    fn matches(&self, entry: &Entry, now: u64) -> bool {
+        self.older_than_days
+            .is_none_or(|days| entry.created + days * 24 * 60 * 60 < now)
+            && self.provider.as_ref().is_none_or(|p| *p == entry.provider)
+            && self.model.as_ref().is_none_or(|m| *m == entry.model)
+            && self.language.as_ref().is_none_or(|l| *l == entry.language)
+    }
+}
+
+/// Removes the matching entries, returns the number removed.
+pub fn prune(path: &Path, prune: &Prune) -> io::Result<usize> {
+    let entries = read_entries(path)?;
+    let now = now();
+    let size = entries.len();
+    let kept: Vec<Entry> = entries
+        .into_iter()
+        .filter(|entry| !prune.matches(entry, now))
+        .collect();
+    write_entries(path, &kept)?;
+    Ok(size - kept.len())
+}
+
+/// Export formats of the cache.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    Json,
+    Tsv,
+}
+
+/// Writes the entries of a cache file as a JSON array or as
+/// tab-separated source, translation, language lines.
+pub fn export(path: &Path, format: Format, writer: &mut impl Write) -> io::Result<()> {
+    let entries = read_entries(path)?;
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *writer, &entries)?;
+            writeln!(writer)
+        }
+        Format::Tsv => {
+            let escape = |text: &str| {
+                text.replace('\\', "\\\\")
+                    .replace('\t', "\\t")
+                    .replace('\n', "\\n")
+            };
+            for entry in entries {
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}",
+                    escape(&entry.source),
+                    escape(&entry.translation),
+                    entry.language
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: Key = Key {
+        provider: "OpenAI",
+        model: "gpt-4o",
+        language: "Japanese",
+        prompt_version: 1,
+    };
+
+    fn segment(text: &str, context: Option<&str>) -> Segment {
+        Segment {
+            text: text.to_string(),
+            context: context.map(str::to_string),
+        }
+    }
+
+    fn entry(key: &str, provider: &str, created: u64) -> Entry {
+        Entry {
+            key: key.to_string(),
+            provider: provider.to_string(),
+            model: "model".to_string(),
+            language: "Japanese".to_string(),
+            source: format!("source\t{key}"),
+            translation: format!("translation\n{key}"),
+            created,
+        }
+    }
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("trans-epub-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn hash_depends_on_every_part() {
+        let hash = KEY.hash(&segment("Hello.", None));
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, KEY.hash(&segment("Hello.", None)));
+        let others = [
+            KEY.hash(&segment("Hello!", None)),
+            KEY.hash(&segment("Hello.", Some("Chapter 1"))),
+            Key {
+                model: "gpt-4o-mini",
+                ..KEY
+            }
+            .hash(&segment("Hello.", None)),
+            Key {
+                prompt_version: 2,
+                ..KEY
+            }
+            .hash(&segment("Hello.", None)),
+        ];
+        assert!(others.iter().all(|other| *other != hash));
+        // the parts are length prefixed
+        assert_ne!(
+            Key {
+                language: "Japan",
+                ..KEY
+            }
+            .hash(&segment("ese", None)),
+            Key {
+                language: "Japanese",
+                ..KEY
+            }
+            .hash(&segment("", None)),
+        );
+    }
+
+    #[test]
+    fn open_keeps_the_last_line_of_a_key() {
+        let path = path("compact.jsonl");
+        let lines: Vec<String> = [
+            entry("a", "old", 1),
+            entry("b", "b", 1),
+            entry("a", "new", 2),
+        ]
+        .iter()
+        .map(|entry| serde_json::to_string(entry).unwrap())
+        .collect();
+        fs::write(&path, lines.join("\n") + "\n{\"key\":").unwrap();
+        let cache = Cache::open(&path).unwrap();
+        assert_eq!(cache.get("a").as_deref(), Some("translation\na"));
+        drop(cache);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.contains("\"provider\":\"new\""));
+        assert!(!content.contains("\"provider\":\"old\""));
+    }
+
+    #[test]
+    fn prune_removes_matching_entries() {
+        let path = path("prune.jsonl");
+        let now = now();
+        let entries = [
+            entry("a", "OpenAI", now - 10 * 24 * 60 * 60),
+            entry("b", "DeepL", now - 10 * 24 * 60 * 60),
+            entry("c", "OpenAI", now),
+        ];
+        write_entries(&path, &entries).unwrap();
+        let removed = prune(
+            &path,
+            &Prune {
+                older_than_days: Some(7),
+                provider: Some("OpenAI".to_string()),
+                model: None,
+                language: None,
+            },
+        )
+        .unwrap();
+        let keys: Vec<String> = read_entries(&path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(keys, ["b", "c"]);
+    }
+
+    #[test]
+    fn export_writes_json_or_escaped_tsv() {
+        let path = path("export.jsonl");
+        write_entries(&path, &[entry("a", "OpenAI", 1)]).unwrap();
+        let mut tsv = vec![];
+        export(&path, Format::Tsv, &mut tsv).unwrap();
+        let mut json = vec![];
+        export(&path, Format::Json, &mut json).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "source\\ta\ttranslation\\na\tJapanese\n"
+        );
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[0]["key"], "a");
+        assert_eq!(json[0]["translation"], "translation\na");
+    }
+}
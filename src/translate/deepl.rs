@@ -0,0 +1,311 @@
+use crate::client::deepl::{Request, request};
+use crate::epub::placeholder_regex;
+use crate::error::Error;
+use crate::translate::translator::{Batch, Capabilities, Context, Segment, Translator, Usage};
+use log::warn;
+use quick_xml::escape::{escape, unescape};
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Most texts DeepL takes in a request.
+const MAX_TEXTS: usize = 50;
+
+/// The end tag DeepL may add after a placeholder, `<x id="1"></x>`.
+static PLACEHOLDER_END: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"</x\s*>").unwrap());
+
+/// Language names and the DeepL target language codes.
+const LANGUAGES: [(&str, &str); 36] = [
+    ("arabic", "AR"),
+    ("bulgarian", "BG"),
+    ("czech", "CS"),
+    ("danish", "DA"),
+    ("german", "DE"),
+    ("greek", "EL"),
+    ("english", "EN-US"),
+    ("american english", "EN-US"),
+    ("british english", "EN-GB"),
+    ("spanish", "ES"),
+    ("estonian", "ET"),
+    ("finnish", "FI"),
+    ("french", "FR"),
+    ("hungarian", "HU"),
+    ("indonesian", "ID"),
+    ("italian", "IT"),
+    ("japanese", "JA"),
+    ("korean", "KO"),
+    ("lithuanian", "LT"),
+    ("latvian", "LV"),
+    ("norwegian", "NB"),
+    ("dutch", "NL"),
+    ("polish", "PL"),
+    ("portuguese", "PT-PT"),
+    ("european portuguese", "PT-PT"),
+    ("brazilian portuguese", "PT-BR"),
+    ("romanian", "RO"),
+    ("russian", "RU"),
+    ("slovak", "SK"),
+    ("slovenian", "SL"),
+    ("swedish", "SV"),
+    ("turkish", "TR"),
+    ("ukrainian", "UK"),
+    ("chinese", "ZH-HANS"),
+    ("simplified chinese", "ZH-HANS"),
+    ("traditional chinese", "ZH-HANT"),
+];
+
+/// Formality of the translation, for the target languages supporting it.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Formality {
+    Default,
+    More,
+    Less,
+    PreferMore,
+    PreferLess,
+}
+
+impl Formality {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::More => "more",
+            Self::Less => "less",
+            Self::PreferMore => "prefer_more",
+            Self::PreferLess => "prefer_less",
+        }
+    }
+}
+
+/// The DeepL API, a machine translation service rather than a language model.
+pub struct DeepL {
+    /// Base URL of the API, without `/v2/translate`.
+    pub base_url: String,
+    pub target_lang: String,
+    pub source_lang: Option<String>,
+    pub formality: Formality,
+    pub glossary_id: Option<String>,
+}
+
+impl DeepL {
+    /// A backend translating into `language`, a language name or a DeepL
+    /// code, from `source`, detected when `None`. The endpoint is the free
+    /// one for a key ending with `:fx`, unless `base_url` is given.
+    pub fn new(
+        api_key: &str,
+        base_url: Option<String>,
+        language: &str,
+        source: Option<&str>,
+        formality: Formality,
+        glossary_id: Option<String>,
+    ) -> Result<Self, Error> {
+        let target = target_lang(language).ok_or_else(|| unsupported(language))?;
+        let source = source
+            .map(|source| {
+                target_lang(source)
+                    .map(|code| source_lang(&code))
+                    .ok_or_else(|| unsupported(source))
+            })
+            .transpose()?;
+        let base_url = base_url.unwrap_or_else(|| {
+            if api_key.ends_with(":fx") {
+                "https://api-free.deepl.com".to_string()
+            } else {
+                "https://api.deepl.com".to_string()
+            }
+        });
+        Ok(Self {
+            base_url,
+            target_lang: target,
+            source_lang: source,
+            formality,
+            glossary_id,
+        })
+    }
+
+    /// What the cache records as the model, the settings changing the translation.
+    pub fn model(&self) -> String {
+        let mut model = format!("formality={}", self.formality.as_str());
+        if let Some(source_lang) = &self.source_lang {
+            model.push_str(&format!(",source={source_lang}"));
+        }
+        if let Some(glossary_id) = &self.glossary_id {
+            model.push_str(&format!(",glossary={glossary_id}"));
+        }
+        model
+    }
+}
+
+impl Translator for DeepL {
+    fn provider(&self) -> &'static str {
+        "deepl"
+    }
+
+    /// There is no prompt, the version follows how the texts are sent.
+    fn prompt_version(&self) -> u32 {
+        1
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            context: true,
+            max_lines: Some(MAX_TEXTS),
+        }
+    }
+
+    async fn translate_batch(&self, context: &Context, lines: &[Segment]) -> Result<Batch, Error> {
+        let texts: Vec<String> = lines.iter().map(|line| to_xml(&line.text)).collect();
+        // the context is for the whole request
+        let mut contexts: Vec<&str> = vec![];
+        for line in lines {
+            if let Some(context) = &line.context
+                && !contexts.contains(&context.as_str())
+            {
+                contexts.push(context);
+            }
+        }
+        let contexts = contexts.join("\n");
+        let response = request(
+            &self.base_url,
+            &context.api_key,
+            &Request {
+                text: &texts,
+                target_lang: &self.target_lang,
+                source_lang: self.source_lang.as_deref(),
+                formality: (self.formality != Formality::Default).then(|| self.formality.as_str()),
+                glossary_id: self.glossary_id.as_deref(),
+                context: (!contexts.is_empty()).then_some(contexts.as_str()),
+            },
+        )
+        .await?;
+
+        Ok(Batch {
+            lines: response.texts.iter().map(|text| from_xml(text)).collect(),
+            usage: Usage {
+                characters: response.billed_characters,
+                ..Usage::default()
+            },
+        })
+    }
+}
+
+/// The DeepL target language code of a language name or code.
+fn target_lang(language: &str) -> Option<String> {
+    let name = language.trim().to_lowercase();
+    if let Some((_, code)) = LANGUAGES.iter().find(|(language, _)| *language == name) {
+        return Some(code.to_string());
+    }
+    let code = name.to_uppercase();
+    // a code as is, EN, PT and ZH need a variant as a target
+    match code.as_str() {
+        "EN" => Some("EN-US".to_string()),
+        "PT" => Some("PT-PT".to_string()),
+        "ZH" => Some("ZH-HANS".to_string()),
+        _ => LANGUAGES
+            .iter()
+            .any(|(_, known)| *known == code)
+            .then_some(code),
+    }
+}
+
+fn unsupported(language: &str) -> Error {
+    Error::UnsupportedLanguage {
+        provider: "DeepL",
+        language: language.to_string(),
+    }
+}
+
+/// The source language code of a target language code, without the variant.
+fn source_lang(code: &str) -> String {
+    code.split('-').next().unwrap_or(code).to_string()
+}
+
+/// The segment text as XML, with the placeholders as tags.
+fn to_xml(text: &str) -> String {
+    let mut xml = String::new();
+    let mut last = 0;
+    for captures in placeholder_regex().captures_iter(text) {
+        let all = captures.get(0).unwrap();
+        xml.push_str(&escape(&text[last..all.start()]));
+        xml.push_str(&format!("<x id=\"{}\"/>", &captures[1]));
+        last = all.end();
+    }
+    xml.push_str(&escape(&text[last..]));
+    xml
+}
+
+/// The translated XML as segment text, the placeholders are kept without
+/// an end tag.
+fn from_xml(xml: &str) -> String {
+    let xml = PLACEHOLDER_END.replace_all(xml, "");
+    match unescape(&xml) {
+        Ok(text) => text.into_owned(),
+        Err(e) => {
+            warn!("{e}: {xml}");
+            xml.into_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_names_and_codes_map_to_deepl_codes() {
+        assert_eq!(target_lang("Japanese").as_deref(), Some("JA"));
+        assert_eq!(target_lang(" British English ").as_deref(), Some("EN-GB"));
+        assert_eq!(target_lang("ja").as_deref(), Some("JA"));
+        assert_eq!(target_lang("pt-br").as_deref(), Some("PT-BR"));
+        // bare codes get the default variant
+        assert_eq!(target_lang("EN").as_deref(), Some("EN-US"));
+        assert_eq!(target_lang("pt").as_deref(), Some("PT-PT"));
+        assert_eq!(target_lang("ZH").as_deref(), Some("ZH-HANS"));
+        assert_eq!(target_lang("Klingon"), None);
+        assert_eq!(target_lang("XX"), None);
+
+        assert_eq!(source_lang("EN-US"), "EN");
+        assert_eq!(source_lang("ZH-HANT"), "ZH");
+        assert_eq!(source_lang("JA"), "JA");
+    }
+
+    #[test]
+    fn placeholders_are_tags_in_the_xml() {
+        let text = r#"a < b & "c"<x id="1"/>d<x id=2>"#;
+        let xml = to_xml(text);
+        assert_eq!(
+            xml,
+            r#"a &lt; b &amp; &quot;c&quot;<x id="1"/>d<x id="2"/>"#
+        );
+        assert_eq!(from_xml(&xml), r#"a < b & "c"<x id="1"/>d<x id="2"/>"#);
+        assert_eq!(from_xml(r#"A <x id="1"></x> B"#), r#"A <x id="1"> B"#);
+        assert_eq!(from_xml(r#"<x id="1"/></x >"#), r#"<x id="1"/>"#);
+    }
+
+    #[test]
+    fn model_records_the_settings() {
+        let deepl = DeepL::new(
+            "key:fx",
+            None,
+            "Japanese",
+            Some("english"),
+            Formality::More,
+            Some("g1".to_string()),
+        )
+        .unwrap();
+        assert_eq!(deepl.base_url, "https://api-free.deepl.com");
+        assert_eq!(deepl.target_lang, "JA");
+        assert_eq!(deepl.model(), "formality=more,source=EN,glossary=g1");
+
+        let deepl = DeepL::new("key", None, "ja", None, Formality::Default, None).unwrap();
+        assert_eq!(deepl.base_url, "https://api.deepl.com");
+        assert_eq!(deepl.model(), "formality=default");
+
+        assert!(matches!(
+            DeepL::new("key", None, "Klingon", None, Formality::Default, None),
+            Err(Error::UnsupportedLanguage {
+                provider: "DeepL",
+                ..
+            })
+        ));
+    }
+}
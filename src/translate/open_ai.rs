@@ -1,7 +1,7 @@
-use crate::client::open_ai::{Ratelimit, Stats, request};
-use crate::translate::translator::Context;
-use futures::{StreamExt, stream};
-use log::{debug, error, trace};
+use crate::client::open_ai::request;
+use crate::error::Error;
+use crate::translate::translator::{Batch, Context, Segment, Translator, Usage, paragraphs};
+use log::error;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -14,111 +14,58 @@ struct ChoiceContentResult {
     translated: Vec<String>,
 }
 
-pub struct BulkTranslated {
-    pub number: i32,
-    pub original_lines: Vec<String>,
-    pub translated_lines: Vec<String>,
-    pub stats: Stats,
-    pub ratelimit: Ratelimit,
+/// Version of the prompt, shared with Azure OpenAI.
+pub(crate) const PROMPT_VERSION: u32 = 1;
+
+/// The OpenAI chat completions API, or a server compatible with it.
+pub struct OpenAi {
+    /// Base URL of the API, without `/chat/completions`.
+    pub base_url: String,
+    /// Sent as the `OpenAI-Organization` header.
+    pub organization: Option<String>,
+    /// Sent as the `OpenAI-Project` header.
+    pub project: Option<String>,
+    /// Headers sent with every request, besides the API key.
+    pub headers: Vec<(String, String)>,
 }
 
-pub async fn translate(context: &Context, lines: Vec<String>) -> Vec<String> {
-    debug!("line_length:{}", lines.len());
-    if lines.is_empty() {
-        return lines;
+impl OpenAi {
+    fn headers(&self, api_key: &str) -> Vec<(String, String)> {
+        let mut headers = vec![("Authorization".to_string(), format!("Bearer {api_key}"))];
+        if let Some(organization) = &self.organization {
+            headers.push(("OpenAI-Organization".to_string(), organization.clone()));
+        }
+        if let Some(project) = &self.project {
+            headers.push(("OpenAI-Project".to_string(), project.clone()));
+        }
+        headers.extend(self.headers.iter().cloned());
+        headers
     }
-    translate_parallel(
-        &context.language,
-        &context.model,
-        &context.api_key,
-        lines,
-        context.lines,
-        context.requests,
-        0,
-    )
-    .await
 }
 
-async fn translate_parallel(
-    language: &String,
-    model: &String,
-    api_key: &String,
-    lines: Vec<String>,
-    chunk_lines: usize,
-    requests: usize,
-    retry_count: i32,
-) -> Vec<String> {
-    let mut number = 0;
-    let bodies = stream::iter(lines.chunks(chunk_lines))
-        .map(|chunked| {
-            let language = language.clone();
-            let model = model.clone();
-            let api_key = api_key.clone();
-            number += 1;
-            let order_number = number;
-            async move {
-                translate_bulk(order_number, &language, &model, &api_key, chunked.to_vec()).await
-            }
-        })
-        .buffer_unordered(requests);
-
-    let mut responses = vec![];
-    let mut bodies_stream = bodies;
-    while let Some(response) = bodies_stream.next().await {
-        responses.push(response);
+impl Translator for OpenAi {
+    fn provider(&self) -> &'static str {
+        "open-ai"
     }
 
-    responses.sort_by(|a, b| a.number.cmp(&b.number));
-    let mut translated = vec![];
-    for response in responses {
-        let mut translated_lines = response.translated_lines;
-        let original_lines = response.original_lines;
-        response.stats.log();
-        response.ratelimit.log();
-        if translated_lines.len() != original_lines.len() {
-            if retry_count > 4 {
-                panic!("retry max error");
-            }
-            for l in &original_lines {
-                trace!("{l}");
-            }
-            for l in &translated_lines {
-                trace!("{l}");
-            }
-            error!("retry count: {retry_count}");
-            error!(
-                "translated line length error {}/{}",
-                translated_lines.len(),
-                original_lines.len()
-            );
-            translated_lines = Box::pin(translate_parallel(
-                language,
-                model,
-                api_key,
-                original_lines,
-                1,
-                requests,
-                retry_count + 1,
-            ))
-            .await;
-        }
-        translated.append(&mut translated_lines);
+    fn prompt_version(&self) -> u32 {
+        PROMPT_VERSION
     }
-    translated
-}
 
-async fn translate_bulk(
-    number: i32,
-    language: &String,
-    model: &str,
-    api_key: &String,
-    original_lines: Vec<String>,
-) -> BulkTranslated {
-    let mut user_contents: Vec<String> = vec![];
-    for line in &original_lines {
-        user_contents.push(format!("<paragraph>{line}</paragraph>"));
+    async fn translate_batch(&self, context: &Context, lines: &[Segment]) -> Result<Batch, Error> {
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        chat_completions(&url, &self.headers(&context.api_key), context, lines).await
     }
+}
 
+/// Translates the segments with a chat completions request to `url`, the
+/// `headers` carry the API key.
+pub(crate) async fn chat_completions(
+    url: &str,
+    headers: &[(String, String)],
+    context: &Context,
+    lines: &[Segment],
+) -> Result<Batch, Error> {
     let prompt = format!(
         "You are an excellent translator.\
         Translate it into {}. Please output the following JSON.\
@@ -130,36 +77,30 @@ async fn translate_bulk(
         Please output the number of the input paragraph.\
         The value of `translated` Key is an array of String type.\
         If a paragraph of input is translated and a paragraph consists of multiple sentences, output an array consisting of multiple String.\
-        Please remove `<paragraph>` and `</paragraph>` tags from the translation result.",
-        language,
-        &original_lines.len(),
-        &original_lines.len()
+        Please remove `<paragraph>` and `</paragraph>` tags from the translation result.\
+        A string in `<context>` tag to `</context>` tag is not a paragraph but reference information for the following paragraphs, please do not output it.\
+        Please keep `<x id=\"1\"/>` style tags in a paragraph unchanged at the corresponding position of the translation result.",
+        context.language,
+        lines.len(),
+        lines.len()
     );
 
-    let response = request(model, api_key, &prompt, &user_contents)
-        .await
-        .expect("OpenAI API Request Error");
-    let choice_content = serde_json::from_str::<ChoiceContent>(response.choice.trim());
-    if choice_content.is_err() {
-        error!("JSON Parse error choice:{}", &response.choice.trim());
-        return BulkTranslated {
-            number,
-            original_lines,
-            translated_lines: vec![],
-            stats: response.stats,
-            ratelimit: response.ratelimit,
-        };
-    }
-    let mut translated_lines = vec![];
-    for result in choice_content.unwrap().results {
-        translated_lines.push(result.translated.join("\n"));
-    }
+    let response = request(url, headers, &context.model, &prompt, &paragraphs(lines)).await?;
+    response.ratelimit.log();
+    let choice_content = serde_json::from_str::<ChoiceContent>(response.choice.trim())
+        .inspect_err(|_| error!("JSON Parse error choice:{}", &response.choice.trim()))?;
 
-    BulkTranslated {
-        number,
-        original_lines,
-        translated_lines,
-        stats: response.stats,
-        ratelimit: response.ratelimit,
-    }
+    Ok(Batch {
+        lines: choice_content
+            .results
+            .into_iter()
+            .map(|result| result.translated.join("\n"))
+            .collect(),
+        usage: Usage {
+            prompt_tokens: response.stats.prompt_tokens as u64,
+            completion_tokens: response.stats.completion_tokens as u64,
+            total_tokens: response.stats.total_tokens as u64,
+            ..Usage::default()
+        },
+    })
 }
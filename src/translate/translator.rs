@@ -1,23 +1,431 @@
-use crate::translate::gemini::translate as gemini;
-use crate::translate::open_ai::translate as open_ai;
+use crate::error::Error;
+use crate::translate::cache::{Cache, Key};
+use futures::{StreamExt, stream};
+use log::{debug, error, info, trace};
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Rounds of retries before a line is given up.
+const MAX_RETRIES: i32 = 5;
+/// Wait before the first round of retries, doubled for each following round.
+const BACKOFF: Duration = Duration::from_millis(500);
+
+/// A unit of text to translate.
+#[derive(Clone)]
+pub struct Segment {
+    pub text: String,
+    /// Reference information for the translation, not translated itself.
+    pub context: Option<String>,
+}
+
 pub struct Context {
     pub model: String,
     pub api_key: String,
     pub language: String,
     pub lines: usize,
     pub requests: usize,
+    pub cache: Option<Cache>,
+    /// Translations of the current job, kept even without the cache.
+    pub checkpoint: Option<Cache>,
+    /// Set on the first interrupt signal, no new request is issued after it.
+    pub interrupted: Arc<AtomicBool>,
+}
+
+impl Context {
+    /// A context translating into `language` 20 segments a request, 5
+    /// requests at a time, without an API key, a cache or a checkpoint.
+    pub fn new(language: impl Into<String>) -> Self {
+        Self {
+            model: String::new(),
+            api_key: String::new(),
+            language: language.into(),
+            lines: 20,
+            requests: 5,
+            cache: None,
+            checkpoint: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// What a backend can take in a request.
+pub struct Capabilities {
+    /// The context of a segment is sent along with it, otherwise it is dropped.
+    pub context: bool,
+    /// Most segments in a request, whatever `--lines` says.
+    pub max_lines: Option<usize>,
 }
 
-pub enum Translator {
-    OpenAi(Context),
-    Gemini(Context),
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            context: true,
+            max_lines: None,
+        }
+    }
 }
 
-impl Translator {
-    pub async fn translate(&self, lines: Vec<String>) -> Vec<String> {
-        match self {
-            Self::OpenAi(context) => open_ai(context, lines).await,
-            Self::Gemini(context) => gemini(context, lines).await,
+/// The segments as `<paragraph>` lines, each preceded by its context in a
+/// `<context>` line when it differs from the previous one.
+pub fn paragraphs(lines: &[Segment]) -> Vec<String> {
+    let mut user_contents = vec![];
+    let mut last_context = None;
+    for line in lines {
+        if let Some(context) = &line.context
+            && line.context.as_ref() != last_context
+        {
+            user_contents.push(format!("<context>{context}</context>"));
         }
+        last_context = line.context.as_ref();
+        user_contents.push(format!("<paragraph>{}</paragraph>", line.text));
+    }
+    user_contents
+}
+
+/// Tokens used by requests, or characters for a backend billing them.
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub characters: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.characters += other.characters;
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.characters > 0 && self.total_tokens == 0 {
+            return write!(f, "characters: {}", self.characters);
+        }
+        write!(
+            f,
+            "prompt tokens: {} completion tokens: {} total tokens: {}",
+            self.prompt_tokens, self.completion_tokens, self.total_tokens
+        )
+    }
+}
+
+/// The translated lines of a request, one for each segment sent when it went well.
+pub struct Batch {
+    pub lines: Vec<String>,
+    pub usage: Usage,
+}
+
+/// A translation backend, the request and response of one provider. Chunking,
+/// ordering, retries and the cache are left to the [`Driver`].
+pub trait Translator {
+    /// Name of the provider, part of the cache key.
+    fn provider(&self) -> &'static str;
+
+    /// Version of the prompt, part of the cache key, bump it when the prompt changes.
+    fn prompt_version(&self) -> u32;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Translates the segments in a single request.
+    fn translate_batch(
+        &self,
+        context: &Context,
+        lines: &[Segment],
+    ) -> impl Future<Output = Result<Batch, Error>> + Send;
+}
+
+/// Drives a backend: splits the segments into requests, retries the ones
+/// that fail line by line and keeps the translations in order. At most
+/// `requests` requests are in flight, however many translations are running.
+pub struct Driver<T> {
+    translator: T,
+    context: Context,
+    usage: Mutex<Usage>,
+    permits: Semaphore,
+}
+
+impl<T: Translator> Driver<T> {
+    pub fn new(translator: T, context: Context) -> Self {
+        let permits = Semaphore::new(context.requests.max(1));
+        Self {
+            translator,
+            context,
+            usage: Mutex::new(Usage::default()),
+            permits,
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Usage of all the requests so far.
+    pub fn usage(&self) -> Usage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Translates the lines, the ones in the checkpoint or the cache without a request.
+    /// Only successful translations are stored.
+    pub async fn translate(&self, lines: Vec<Segment>) -> Vec<Result<String, Error>> {
+        let context = self.context();
+        let stores: Vec<&Cache> = [&context.checkpoint, &context.cache]
+            .into_iter()
+            .flatten()
+            .collect();
+        if stores.is_empty() {
+            return self.request(lines).await;
+        }
+        let key = self.key();
+        let mut translated: Vec<Option<Result<String, Error>>> = lines
+            .iter()
+            .map(|line| {
+                let hash = key.hash(line);
+                stores.iter().find_map(|store| store.get(&hash)).map(Ok)
+            })
+            .collect();
+        let misses: Vec<Segment> = lines
+            .iter()
+            .zip(&translated)
+            .filter(|(_, translation)| translation.is_none())
+            .map(|(line, _)| line.clone())
+            .collect();
+        if misses.len() < lines.len() {
+            info!("cache hit {}/{}", lines.len() - misses.len(), lines.len());
+        }
+        if misses.is_empty() {
+            return translated.into_iter().flatten().collect();
+        }
+        let mut results = self.request(misses.clone()).await.into_iter();
+        for (line, translation) in misses.iter().zip(results.by_ref()) {
+            if let Ok(translation) = &translation {
+                for store in &stores {
+                    store.insert(&key, line, translation);
+                }
+            }
+            if let Some(slot) = translated.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(translation);
+            }
+        }
+        translated.into_iter().flatten().collect()
+    }
+
+    /// Translates the lines, a line that still fails after the retries is an error.
+    async fn request(&self, mut lines: Vec<Segment>) -> Vec<Result<String, Error>> {
+        if lines.is_empty() {
+            return vec![];
+        }
+        let capabilities = self.translator.capabilities();
+        if !capabilities.context {
+            for line in &mut lines {
+                line.context = None;
+            }
+        }
+        let chunk_lines = self.context.lines.max(1);
+        let chunk_lines = capabilities
+            .max_lines
+            .map_or(chunk_lines, |max_lines| chunk_lines.min(max_lines.max(1)));
+        self.translate_chunks(&lines, chunk_lines).await
+    }
+
+    /// Translates the lines in chunks of `chunk_lines`. The lines of a chunk
+    /// that fails are retried one by one in the next round, after a backoff
+    /// or the delay the server asked for, whichever is longer.
+    async fn translate_chunks(
+        &self,
+        lines: &[Segment],
+        chunk_lines: usize,
+    ) -> Vec<Result<String, Error>> {
+        let mut translated: Vec<Option<Result<String, Error>>> =
+            std::iter::repeat_with(|| None).take(lines.len()).collect();
+        let mut chunks: Vec<Range<usize>> = (0..lines.len())
+            .step_by(chunk_lines)
+            .map(|start| start..(start + chunk_lines).min(lines.len()))
+            .collect();
+        let mut retry_count = 0;
+        while !chunks.is_empty() {
+            let responses: Vec<(Range<usize>, Result<Batch, Error>)> = stream::iter(chunks)
+                .map(|range| async move {
+                    let batch = self.translate_batch(&lines[range.clone()]).await;
+                    (range, batch)
+                })
+                .buffer_unordered(self.context.requests.max(1))
+                .collect()
+                .await;
+
+            let mut failed = vec![];
+            let mut retry_after = Duration::ZERO;
+            for (range, batch) in responses {
+                let original_lines = &lines[range.clone()];
+                let error = match batch {
+                    Ok(batch) => {
+                        info!("{}", batch.usage);
+                        self.usage.lock().unwrap().add(batch.usage);
+                        if batch.lines.len() == original_lines.len() {
+                            for (slot, line) in translated[range].iter_mut().zip(batch.lines) {
+                                *slot = Some(Ok(line));
+                            }
+                            continue;
+                        }
+                        for l in original_lines {
+                            trace!("{}", l.text);
+                        }
+                        for l in &batch.lines {
+                            trace!("{l}");
+                        }
+                        Error::LineCount {
+                            translated: batch.lines.len(),
+                            expected: original_lines.len(),
+                        }
+                    }
+                    Err(e) => e,
+                };
+                error!("retry count: {retry_count}");
+                error!("{error}");
+                // an interrupted run issues no new request, not even a retry
+                if retry_count >= MAX_RETRIES || self.context.interrupted.load(Ordering::SeqCst) {
+                    let reason = error.to_string();
+                    for slot in &mut translated[range] {
+                        *slot = Some(Err(Error::Retry {
+                            retries: retry_count,
+                            reason: reason.clone(),
+                        }));
+                    }
+                    continue;
+                }
+                retry_after = retry_after.max(error.retry_after().unwrap_or_default());
+                failed.extend(range.map(|line| line..line + 1));
+            }
+            if !failed.is_empty() {
+                let wait = retry_after.max(BACKOFF * 2u32.pow(retry_count as u32));
+                debug!("retry {} lines in {}sec", failed.len(), wait.as_secs_f64());
+                tokio::time::sleep(wait).await;
+            }
+            chunks = failed;
+            retry_count += 1;
+        }
+        translated.into_iter().flatten().collect()
+    }
+
+    /// Sends a request once one of the `requests` permits is free.
+    async fn translate_batch(&self, lines: &[Segment]) -> Result<Batch, Error> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        self.translator.translate_batch(&self.context, lines).await
+    }
+
+    fn key(&self) -> Key<'_> {
+        Key {
+            provider: self.translator.provider(),
+            model: &self.context.model,
+            language: &self.context.language,
+            prompt_version: self.translator.prompt_version(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+
+    /// Echoes the lines, failing the first `failures` requests with a 429.
+    #[derive(Default)]
+    struct Echo {
+        failures: AtomicUsize,
+        in_flight: AtomicUsize,
+        most_in_flight: AtomicUsize,
+    }
+
+    impl Translator for Echo {
+        fn provider(&self) -> &'static str {
+            "echo"
+        }
+
+        fn prompt_version(&self) -> u32 {
+            1
+        }
+
+        async fn translate_batch(&self, _: &Context, lines: &[Segment]) -> Result<Batch, Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(Error::Status {
+                    status: 429,
+                    retry_after: Some(Duration::from_millis(700)),
+                });
+            }
+            Ok(Batch {
+                lines: lines.iter().map(|line| line.text.clone()).collect(),
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    fn driver(echo: Echo, lines: usize, requests: usize) -> Driver<Echo> {
+        Driver::new(
+            echo,
+            Context {
+                lines,
+                requests,
+                ..Context::new("")
+            },
+        )
+    }
+
+    fn segments(count: usize) -> Vec<Segment> {
+        (0..count)
+            .map(|i| Segment {
+                text: i.to_string(),
+                context: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn requests_are_capped_across_translations() {
+        let driver = driver(Echo::default(), 1, 2);
+        let translations =
+            futures::future::join_all((0..4).map(|_| driver.translate(segments(5)))).await;
+        assert!(translations.iter().flatten().all(Result::is_ok));
+        assert_eq!(driver.translator.most_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_wait_as_asked_and_keep_the_order() {
+        let echo = Echo {
+            failures: AtomicUsize::new(1),
+            ..Echo::default()
+        };
+        let driver = driver(echo, 3, 1);
+        let start = Instant::now();
+        let translations: Vec<String> = driver
+            .translate(segments(3))
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert!(start.elapsed() >= Duration::from_millis(700));
+        assert_eq!(translations, ["0", "1", "2"]);
     }
 }